@@ -0,0 +1,167 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::markdown::{self, find_files, CodeBlock};
+use crate::project::ProjectPool;
+use crate::rustdoc::{self, DoctestCrate};
+use crate::twoslash::{DiagnosticCategory, QueryKind};
+
+/// Something wrong with a snippet, which fails the check.
+pub struct Problem {
+    pub file: PathBuf,
+    /// The line of the file, counting from 1
+    pub line: u32,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file.display(), self.line, self.message)
+    }
+}
+
+/// A twoslash block, and the code it is analyzed as.
+struct Snippet {
+    block: CodeBlock,
+    code: String,
+    /// The line of the block each line of `code` comes from, `None` for lines added to it
+    lines: Vec<Option<u32>>,
+}
+
+impl Snippet {
+    /// The line of the file a line of the code is on. Lines added to the code are put on the
+    /// first line of the block.
+    fn file_line(&self, line: u32) -> u32 {
+        let line = self.lines.get(line as usize).copied().flatten();
+        // The code starts on the line after the opening fence.
+        self.block.line + line.unwrap_or(0) + 2
+    }
+}
+
+/// The twoslash blocks of a markdown file, or of the doc comments of a Rust file. Doctests are
+/// analyzed the way the `rustdoc` subcommand analyzes them, against the crate the file is in.
+fn snippets(file: &Path) -> Result<Vec<Snippet>> {
+    let source =
+        fs::read_to_string(file).with_context(|| format!("could not read {}", file.display()))?;
+    if file.extension().is_none_or(|extension| extension != "rs") {
+        let snippets = markdown::code_blocks(&source)
+            .into_iter()
+            .filter(CodeBlock::should_twoslash)
+            .map(|block| Snippet {
+                code: block.code.clone(),
+                lines: (0..block.code.lines().count() as u32).map(Some).collect(),
+                block,
+            })
+            .collect();
+        return Ok(snippets);
+    }
+
+    let blocks: Vec<_> = rustdoc::doc_code_blocks(&source)
+        .into_iter()
+        .filter(rustdoc::should_twoslash)
+        .collect();
+    if blocks.is_empty() {
+        return Ok(vec![]);
+    }
+    let krate = DoctestCrate::of_file(file)?;
+    let snippets = blocks
+        .into_iter()
+        .map(|block| {
+            let (code, lines) = krate.snippet_lines(&block.code);
+            Snippet { block, code, lines }
+        })
+        .collect();
+    Ok(snippets)
+}
+
+/// Twoslashes every snippet in the markdown and Rust files at `paths`, and finds the problems
+/// with them: errors in snippets not marked `compile_fail`, `compile_fail` snippets without
+/// errors, queries with no answer, and markers outside the cut or with no line to point at.
+pub fn check(projects: &mut ProjectPool, paths: &[PathBuf]) -> Result<Vec<Problem>> {
+    let mut problems = vec![];
+    for path in paths {
+        for file in find_files(path, &["md", "mdx", "rs"])? {
+            for snippet in snippets(&file)? {
+                check_snippet(projects, &file, &snippet, &mut problems);
+            }
+        }
+    }
+    Ok(problems)
+}
+
+fn check_snippet(
+    projects: &mut ProjectPool,
+    file: &Path,
+    snippet: &Snippet,
+    problems: &mut Vec<Problem>,
+) {
+    let mut problem = |line: u32, message: String| {
+        problems.push(Problem {
+            file: file.to_path_buf(),
+            line: snippet.file_line(line),
+            message,
+        })
+    };
+
+    let result = projects
+        .project(&snippet.code)
+        .and_then(|project| Ok((project.twoslasher()?, project)));
+    let (result, project) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            problem(0, format!("could not analyze the snippet: {:#}", err));
+            return;
+        }
+    };
+
+    let compile_fail = snippet.block.has_attribute("compile_fail");
+    if let Some(error) = &result.bootstrap_error {
+        problem(0, error.to_string());
+    }
+    // Markers with nothing to point at are reported with the other markers, on their own line.
+    let errors: Vec<_> = result
+        .errors
+        .iter()
+        .filter(|error| matches!(error.category, DiagnosticCategory::Error))
+        .filter(|error| error.id != "invalid_marker")
+        .collect();
+    if compile_fail && errors.is_empty() && result.bootstrap_error.is_none() {
+        problem(
+            0,
+            "expected an error in a `compile_fail` snippet".to_string(),
+        );
+    }
+    if !compile_fail {
+        for error in errors {
+            problem(
+                project.result_line(error.line),
+                format!("error: {}", error.rendered_message),
+            );
+        }
+    }
+
+    for marker in project.markers() {
+        let what = match marker.kind {
+            QueryKind::Query => "query",
+            QueryKind::Completions => "completions query",
+            QueryKind::ConstEval => "const eval query",
+            QueryKind::Assist => "assist",
+        };
+        let message = match &marker.answer {
+            _ if !marker.placed => format!("the {} has no line to point at", what),
+            _ if !marker.in_cut => format!("the {} points outside the cut", what),
+            None => format!("the {} has no answer", what),
+            Some(answer) => match (&answer.error, &answer.completions) {
+                (Some(error), _) => format!("the {} failed: {}", what, error),
+                (None, Some(completions)) if completions.is_empty() => {
+                    format!("the {} has no completions", what)
+                }
+                _ => continue,
+            },
+        };
+        problem(marker.line, message);
+    }
+}
@@ -0,0 +1,249 @@
+use std::cmp::Reverse;
+
+use crate::query_parser::width;
+use crate::twoslash::{Error, Query, TwoSlash};
+
+/// Styles for the HTML from `render`, to be included once per page. Popups are shown on hover
+/// with CSS alone, so pages need no JavaScript.
+pub const STYLE: &str = r#"<style>
+.twoslash-hover { position: relative; border-bottom: 1px dotted currentColor; }
+.twoslash-popup { display: none; position: absolute; left: 0; top: 100%; z-index: 10; width: max-content; max-width: 60ch; padding: 4px 8px; white-space: pre-wrap; color: inherit; background: var(--bg, #fff); border: 1px solid var(--quote-border, #ccc); border-radius: 4px; }
+.twoslash-hover:hover > .twoslash-popup { display: block; }
+.twoslash-highlight { background: rgba(255, 200, 0, 0.25); }
+.twoslash-squiggle { text-decoration: underline wavy #e51400; }
+.twoslash-squiggle.twoslash-warning { text-decoration-color: #bf8803; }
+.twoslash-squiggle.twoslash-info, .twoslash-squiggle.twoslash-debug { text-decoration-color: #1a85ff; }
+.twoslash-error { display: block; padding-left: 8px; white-space: pre-wrap; border-left: 3px solid #e51400; }
+.twoslash-error.twoslash-warning { border-color: #bf8803; }
+.twoslash-error.twoslash-info, .twoslash-error.twoslash-debug { border-color: #1a85ff; }
+.twoslash-query { display: block; }
+.twoslash-query-box { display: inline-block; padding: 0 8px; white-space: pre-wrap; vertical-align: top; border: 1px solid var(--quote-border, #ccc); border-radius: 4px; }
+.twoslash-query-box.twoslash-query-error { border-color: #e51400; }
+.twoslash-completion { display: block; }
+</style>"#;
+
+/// Renders a twoslash result as a self-contained HTML page.
+pub fn document(result: &TwoSlash, tab_width: u32) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n{}\n</head>\n<body>\n{}\n</body>\n</html>",
+        STYLE,
+        render(result, tab_width)
+    )
+}
+
+/// Markup to insert into a line of code.
+struct Event {
+    pos: usize,
+    /// Orders events at the same position: closing tags, then opening tags, then hovers
+    order: u8,
+    /// Where the span the event belongs to ends, so that wider spans open first
+    end: usize,
+    html: String,
+}
+
+const CLOSE: u8 = 0;
+const OPEN: u8 = 1;
+const HOVER: u8 = 2;
+
+/// Renders the code of a twoslash result as HTML, with a popup on every identifier that has a
+/// hover, highlighted and squiggled ranges, and boxes under the lines with queries and errors.
+/// Errors setting up the snippet come before the code, as they aren't about any of it. Tabs take
+/// up `tab_width` columns, as they do when lining up markers.
+pub fn render(result: &TwoSlash, tab_width: u32) -> String {
+    let code = &result.code;
+
+    let mut html = String::new();
+    let (setup_errors, errors): (Vec<&Error>, Vec<&Error>) =
+        result.errors.iter().partition(|error| error.setup);
+    for error in setup_errors {
+        html.push_str(&render_error(error));
+        html.push('\n');
+    }
+    html.push_str(&format!(
+        "<pre class=\"twoslash\" style=\"tab-size: {}\"><code>",
+        tab_width
+    ));
+    let mut offset = 0;
+    for (line, text) in code.split('\n').enumerate() {
+        let end = offset + text.len();
+        let on_line = |start: u32| (offset..=end).contains(&(start as usize));
+
+        // Hovers spanning lines, like the one for the whole module, don't fit inline, there's no
+        // point hovering whitespace, and tokens can be hovered only once.
+        let mut hovers: Vec<(usize, usize, &str)> = result
+            .static_quick_infos
+            .iter()
+            .map(|info| {
+                let start = info.start as usize;
+                (start, start + info.length as usize, info.text.as_str())
+            })
+            .filter(|&(start, token_end, _)| {
+                start >= offset && token_end <= end && !code[start..token_end].trim().is_empty()
+            })
+            .collect();
+        hovers.sort_by_key(|&(start, _, _)| start);
+        let mut last_end = offset;
+        hovers.retain(|&(start, token_end, _)| {
+            let keep = start >= last_end;
+            if keep {
+                last_end = token_end;
+            }
+            keep
+        });
+
+        // Other spans can't start or end in the middle of a hovered token.
+        let splits_hover = |pos: usize| {
+            hovers
+                .iter()
+                .any(|&(start, end, _)| start < pos && pos < end)
+        };
+        let spans = result
+            .highlights
+            .iter()
+            .map(|highlight| {
+                let class = "twoslash-highlight".to_string();
+                (highlight.start, highlight.length, class)
+            })
+            .chain(errors.iter().map(|error| {
+                let class = format!("twoslash-squiggle twoslash-{}", error.category.name());
+                (error.start, error.length, class)
+            }))
+            .filter(|&(start, _, _)| on_line(start))
+            .map(|(start, length, class)| {
+                let start = start as usize;
+                (start, end.min(start + length as usize), class)
+            })
+            .filter(|&(start, end, _)| start < end && !splits_hover(start) && !splits_hover(end))
+            .collect();
+
+        let mut events = vec![];
+        for (start, end, class) in nest(spans) {
+            events.push(Event {
+                pos: start,
+                order: OPEN,
+                end,
+                html: format!("<span class=\"{}\">", class),
+            });
+            events.push(Event {
+                pos: end,
+                order: CLOSE,
+                end,
+                html: "</span>".to_string(),
+            });
+        }
+        for &(start, end, text) in &hovers {
+            events.push(Event {
+                pos: start,
+                order: HOVER,
+                end,
+                html: format!(
+                    "<span class=\"twoslash-hover\">{}<span class=\"twoslash-popup\">{}</span></span>",
+                    escape(&code[start..end]),
+                    escape(text),
+                ),
+            });
+        }
+        events.sort_by_key(|event| (event.pos, event.order, Reverse(event.end)));
+
+        let mut pos = offset;
+        for event in events {
+            html.push_str(&escape(&code[pos..event.pos]));
+            html.push_str(&event.html);
+            // Hovers include their token.
+            pos = match event.order {
+                HOVER => event.end,
+                _ => event.pos,
+            };
+        }
+        html.push_str(&escape(&code[pos..end]));
+        html.push('\n');
+
+        for query in result.queries.iter().filter(|query| on_line(query.start)) {
+            let column = width(&code[offset..query.start as usize], tab_width);
+            html.push_str(&render_query(query, column as usize));
+        }
+        for error in errors.iter().filter(|error| error.line as usize == line) {
+            html.push_str(&render_error(error));
+        }
+        offset = end + 1;
+    }
+    html.push_str("</code></pre>");
+    html
+}
+
+/// Splits spans that cross each other where one ends inside another, so that the pieces nest and
+/// the tags they become are balanced. Spans that nest already are left whole.
+fn nest(mut spans: Vec<(usize, usize, String)>) -> Vec<(usize, usize, String)> {
+    spans.sort_by_key(|&(start, end, _)| (start, Reverse(end)));
+    let mut positions: Vec<usize> = spans
+        .iter()
+        .flat_map(|&(start, end, _)| [start, end])
+        .collect();
+    positions.sort();
+    positions.dedup();
+
+    let mut pieces = vec![];
+    // The spans open at the current position, innermost last, each with where its piece started
+    let mut open: Vec<(usize, usize, String)> = vec![];
+    let mut spans = spans.into_iter().peekable();
+    for pos in positions {
+        // Closing a span closes the ones opened inside it too, which open again unless they end
+        // here as well.
+        if let Some(i) = open.iter().position(|&(_, end, _)| end == pos) {
+            let inner = open.split_off(i);
+            for (start, end, class) in inner {
+                pieces.push((start, pos, class.clone()));
+                if end > pos {
+                    open.push((pos, end, class));
+                }
+            }
+        }
+        while let Some((start, end, class)) = spans.next_if(|&(start, _, _)| start == pos) {
+            open.push((start, end, class));
+        }
+    }
+    pieces
+}
+
+fn render_error(error: &Error) -> String {
+    format!(
+        "<span class=\"twoslash-error twoslash-{}\">{}</span>",
+        error.category.name(),
+        escape(&error.rendered_message),
+    )
+}
+
+/// Renders the answer to a query in a box under the token it asks about, which is shown at
+/// `column`.
+fn render_query(query: &Query, column: usize) -> String {
+    let (class, content) = match (&query.completions, &query.text, &query.error) {
+        (Some(completions), _, _) => (
+            "twoslash-query-box twoslash-completions",
+            completions
+                .iter()
+                .map(|completion| {
+                    format!(
+                        "<span class=\"twoslash-completion\">{}</span>",
+                        escape(&completion.name)
+                    )
+                })
+                .collect(),
+        ),
+        (None, Some(text), _) => ("twoslash-query-box", escape(text)),
+        (None, None, Some(error)) => ("twoslash-query-box twoslash-query-error", escape(error)),
+        (None, None, None) => return String::new(),
+    };
+    format!(
+        "<span class=\"twoslash-query\">{}<span class=\"{}\">{}</span></span>",
+        " ".repeat(column),
+        class,
+        content
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
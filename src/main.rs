@@ -1,17 +1,113 @@
+mod check;
+mod html;
+mod markdown;
+mod mdbook;
+mod nodes;
 mod project;
 #[cfg(test)]
 mod project_test;
 mod protocol;
 mod query_parser;
+mod rustdoc;
+mod terminal;
 mod twoslash;
 
-use project::{Project, ProjectSettings};
+use project::{
+    CrateType, FeatureSettings, HoverSettings, LocalSource, OfflineSettings, ProcMacroServer,
+    Project, ProjectPool, ProjectSettings, WorkspaceSettings,
+};
+use twoslash::{BootstrapError, PositionEncoding, TwoSlash};
 
 use anyhow::Result;
-use std::io::{Read, Write};
+use ra_ide::Edition;
+use std::io::{IsTerminal, Read, Write};
 use std::net::TcpListener;
+use std::path::PathBuf;
+use std::str::FromStr;
 use tempfile::TempDir;
 
+/// The server's answer to `message`: the twoslash result in `format`, or the error that kept it
+/// from having one.
+fn answer(
+    projects: &mut ProjectPool,
+    message: &str,
+    format: &Format,
+    position_encoding: PositionEncoding,
+) -> Result<String> {
+    let twoslash_result = projects.project(message).and_then(|project| {
+        let twoslash_result = project.twoslasher()?;
+        format.to_json(project, twoslash_result, position_encoding)
+    });
+    let answer = match twoslash_result {
+        Ok(twoslash_result) => twoslash_result.to_string(),
+        Err(error) => {
+            let mut answer = serde_json::json!({ "error": format!("{:#}", error) });
+            if let Some(bootstrap_error) = error.downcast_ref::<BootstrapError>() {
+                answer["bootstrapError"] = serde_json::to_value(bootstrap_error)?;
+            }
+            answer.to_string()
+        }
+    };
+    Ok(answer)
+}
+
+/// How the result is printed. The server, `markdown` and `rustdoc` modes print JSON, so only
+/// one-off mode can print HTML or terminal output.
+enum Format {
+    Json,
+    Html,
+    Terminal,
+    /// The `nodes` shape of twoslash v0.2
+    Nodes,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Format::Json),
+            "html" => Ok(Format::Html),
+            "terminal" => Ok(Format::Terminal),
+            "nodes" => Ok(Format::Nodes),
+            _ => Err(anyhow::anyhow!("invalid format: {:?}", s)),
+        }
+    }
+}
+
+impl Format {
+    /// Fails for the formats only one-off mode can print, as the other modes print JSON.
+    fn require_json(&self) -> Result<()> {
+        match self {
+            Format::Json | Format::Nodes => Ok(()),
+            Format::Html => Err(anyhow::anyhow!("--format html only works in one-off mode")),
+            Format::Terminal => Err(anyhow::anyhow!(
+                "--format terminal only works in one-off mode"
+            )),
+        }
+    }
+
+    /// The result as JSON with `position_encoding` positions, in the shape of this format.
+    fn to_json(
+        &self,
+        project: &Project,
+        mut twoslash_result: TwoSlash,
+        position_encoding: PositionEncoding,
+    ) -> Result<serde_json::Value> {
+        self.require_json()?;
+        if let Format::Nodes = self {
+            let nodes = nodes::render(
+                &twoslash_result,
+                |tag| project.tag_line(tag),
+                position_encoding,
+            );
+            return Ok(serde_json::to_value(nodes)?);
+        }
+        twoslash_result.encode_positions(position_encoding);
+        Ok(serde_json::to_value(twoslash_result)?)
+    }
+}
+
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
 
@@ -20,7 +116,7 @@ fn main() -> Result<()> {
         .iter()
         .position(|arg| arg == "--cargo-toml")
         .and_then(|i| args.get(i + 1))
-        .map(|path| std::fs::read_to_string(path))
+        .map(std::fs::read_to_string)
         .transpose()?;
 
     // Parse --target-dir argument
@@ -29,6 +125,159 @@ fn main() -> Result<()> {
         .position(|arg| arg == "--target-dir")
         .and_then(|i| args.get(i + 1).cloned());
 
+    // Parse --cache-dir argument
+    let cache_dir = args
+        .iter()
+        .position(|arg| arg == "--cache-dir")
+        .and_then(|i| args.get(i + 1).cloned());
+
+    // Parse --target argument
+    let target = args
+        .iter()
+        .position(|arg| arg == "--target")
+        .and_then(|i| args.get(i + 1).cloned());
+
+    // Parse --crate-type argument
+    let crate_type = args
+        .iter()
+        .position(|arg| arg == "--crate-type")
+        .and_then(|i| args.get(i + 1))
+        .map(|crate_type| crate_type.parse())
+        .transpose()?
+        .unwrap_or(CrateType::Lib);
+
+    // Parse --edition argument
+    let edition = args
+        .iter()
+        .position(|arg| arg == "--edition")
+        .and_then(|i| args.get(i + 1))
+        .map(|edition| edition.parse())
+        .transpose()?
+        .unwrap_or(Edition::Edition2021);
+
+    // Parse --format argument
+    let format = args
+        .iter()
+        .position(|arg| arg == "--format")
+        .and_then(|i| args.get(i + 1))
+        .map(|format| format.parse())
+        .transpose()?
+        .unwrap_or(Format::Json);
+
+    // Parse --position-encoding argument, which only applies to JSON output as the renderers count
+    // in bytes
+    let position_encoding = args
+        .iter()
+        .position(|arg| arg == "--position-encoding")
+        .and_then(|i| args.get(i + 1))
+        .map(|encoding| encoding.parse())
+        .transpose()?
+        .unwrap_or(PositionEncoding::Utf8);
+
+    // Parse --tab-width argument
+    let tab_width = args
+        .iter()
+        .position(|arg| arg == "--tab-width")
+        .and_then(|i| args.get(i + 1))
+        .map(|width| width.parse())
+        .transpose()?
+        .unwrap_or(4);
+
+    // Parse --adjustments flag
+    let adjustments = args.iter().any(|arg| arg == "--adjustments");
+
+    // Parse --fixes flag
+    let fixes = args.iter().any(|arg| arg == "--fixes");
+
+    // Parse offline arguments
+    let arg_value = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|i| args.get(i + 1))
+    };
+    let offline = OfflineSettings {
+        offline: args.iter().any(|arg| arg == "--offline"),
+        frozen: args.iter().any(|arg| arg == "--frozen"),
+        source: match (arg_value("--vendor"), arg_value("--local-registry")) {
+            (Some(vendor), _) => Some(LocalSource::Vendor(vendor.into())),
+            (None, Some(registry)) => Some(LocalSource::Registry(registry.into())),
+            (None, None) => None,
+        },
+        cargo_lock: arg_value("--cargo-lock")
+            .map(std::fs::read_to_string)
+            .transpose()?,
+    };
+
+    // Parse --strict flag
+    let strict = args.iter().any(|arg| arg == "--strict");
+
+    // Parse --timings flag
+    let timings = args.iter().any(|arg| arg == "--timings");
+
+    // Parse hover flags
+    let parse_limit = |flag: &str| -> Result<Option<Option<usize>>> {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|i| args.get(i + 1))
+            .map(|limit| match limit.as_str() {
+                "all" => Ok(None),
+                limit => Ok(Some(limit.parse()?)),
+            })
+            .transpose()
+    };
+    let default_hover = HoverSettings::default();
+    let hover = HoverSettings {
+        memory_layout: args.iter().any(|arg| arg == "--memory-layout"),
+        keywords: !args.iter().any(|arg| arg == "--no-keyword-docs"),
+        documentation: !args.iter().any(|arg| arg == "--no-hover-docs"),
+        max_trait_assoc_items: parse_limit("--max-trait-assoc-items")?
+            .unwrap_or(default_hover.max_trait_assoc_items),
+        max_fields: parse_limit("--max-fields")?.unwrap_or(default_hover.max_fields),
+        drop_glue: args.iter().any(|arg| arg == "--drop-glue"),
+    };
+
+    // Parse --proc-macro-server argument
+    let proc_macro_server = args
+        .iter()
+        .position(|arg| arg == "--proc-macro-server")
+        .and_then(|i| args.get(i + 1))
+        .map(|server| server.parse())
+        .transpose()?
+        .unwrap_or(ProcMacroServer::None);
+
+    // Parse feature and cfg arguments
+    let mut features = FeatureSettings {
+        all_features: args.iter().any(|arg| arg == "--all-features"),
+        no_default_features: args.iter().any(|arg| arg == "--no-default-features"),
+        ..FeatureSettings::default()
+    };
+    for (flag, value) in args.iter().zip(args.iter().skip(1)) {
+        match flag.as_str() {
+            "--features" => features.add_features(value),
+            "--cfg" => features.add_cfg(value)?,
+            _ => {}
+        }
+    }
+
+    // Parse --workspace and --workspace-package arguments
+    let workspace = arg_value("--workspace").map(|root| WorkspaceSettings {
+        root,
+        package: arg_value("--workspace-package").map(String::as_str),
+    });
+    if workspace.is_some() {
+        // These shape the temporary project, which an existing workspace replaces.
+        let ignored = [
+            "--cargo-toml",
+            "--cache-dir",
+            "--vendor",
+            "--local-registry",
+            "--cargo-lock",
+        ];
+        if let Some(flag) = ignored.iter().find(|flag| arg_value(flag).is_some()) {
+            return Err(anyhow::anyhow!("{flag} can't be used with --workspace"));
+        }
+    }
+
     let tmpdir = TempDir::new()?;
     let default_project_name = "twoslash-rust-project";
     let mut project_settings = ProjectSettings {
@@ -36,6 +285,20 @@ fn main() -> Result<()> {
         tmpdir: &tmpdir,
         cargo_toml: cargo_toml_content.as_deref(),
         target_dir: target_dir.as_deref(),
+        cache_dir: cache_dir.as_deref(),
+        edition,
+        target: target.as_deref(),
+        crate_type,
+        adjustments,
+        fixes,
+        hover,
+        features,
+        proc_macro_server,
+        strict,
+        offline,
+        timings,
+        workspace,
+        tab_width,
     };
 
     if let Ok(server_uuid) = std::env::var("TWOSLASH_SERVER_UUID") {
@@ -59,10 +322,18 @@ fn main() -> Result<()> {
         //
         //  "Shutdown 00uuid" ------------------>  <server shutdown>
         //
+        // Code that can't be analyzed, e.g. because `--strict` is set and cargo fails, is answered
+        // with `{"error": <message>}`, plus `"bootstrapError"` when cargo failed. The server keeps
+        // answering after that.
+        //
         let shutdown_message = format!("Shutdown {}", server_uuid);
 
-        // Create the project that we will reuse between connections to the socket.
-        let mut project = Project::scaffold(project_settings)?;
+        format.require_json()?;
+
+        // Create the projects that we will reuse between connections to the socket. Snippets
+        // declaring dependencies or features get a workspace of their own, shared with any other
+        // snippet declaring the same ones.
+        let mut projects = ProjectPool::new(project_settings)?;
 
         // Start the server side of the socket.
         let server = TcpListener::bind("127.0.0.1:0")?;
@@ -80,14 +351,70 @@ fn main() -> Result<()> {
 
             // The only other messages we permit via this "protocol" (if you can call it that, lol)
             // are code that should be analyzed for twoslash-ing.
-            project = project.apply_change(message);
-            let twoslash_result = project.twoslasher()?;
-
-            protocol::write(&stream, &serde_json::to_string(&twoslash_result)?)?;
+            protocol::write(
+                &stream,
+                &answer(&mut projects, &message, &format, position_encoding)?,
+            )?;
             stream.flush()?;
         }
 
         drop(server);
+    } else if args.get(1).is_some_and(|arg| arg == "mdbook") {
+        // We are being run as an mdBook preprocessor. mdBook first asks whether we support its
+        // renderer, appending `supports <renderer>` to our arguments, then sends the book.
+        if let Some(i) = args.iter().position(|arg| arg == "supports") {
+            let renderer = args.get(i + 1).map(String::as_str).unwrap_or_default();
+            std::process::exit(if mdbook::supports(renderer) { 0 } else { 1 });
+        }
+        let input = {
+            let mut buf = String::new();
+            std::io::stdin().read_to_string(&mut buf)?;
+            buf
+        };
+        let mut projects = ProjectPool::new(project_settings)?;
+        println!("{}", mdbook::preprocess(&mut projects, &input, tab_width)?);
+    } else if args.get(1).is_some_and(|arg| arg == "check") {
+        // We are being asked to check the snippets of markdown and Rust files, given as the
+        // arguments before the first option, failing if any has a problem.
+        let paths: Vec<PathBuf> = args[2..]
+            .iter()
+            .take_while(|arg| !arg.starts_with("--"))
+            .map(PathBuf::from)
+            .collect();
+        let mut projects = ProjectPool::new(project_settings)?;
+        let problems = check::check(&mut projects, &paths)?;
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+    } else if args.get(1).is_some_and(|arg| arg == "rustdoc") {
+        // We are being asked to twoslash the doctests of the crate at the given path.
+        let root = args
+            .get(2)
+            .filter(|arg| !arg.starts_with("--"))
+            .ok_or_else(|| anyhow::anyhow!("missing crate path"))?;
+        format.require_json()?;
+        let mut projects = ProjectPool::new(project_settings)?;
+        let results = rustdoc::twoslash_crate(&mut projects, root.as_ref(), |project, result| {
+            format.to_json(project, result, position_encoding)
+        })?;
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else if args.get(1).is_some_and(|arg| arg == "markdown") {
+        // We are being asked to twoslash the code blocks of markdown files, given as the
+        // arguments before the first option.
+        let paths: Vec<PathBuf> = args[2..]
+            .iter()
+            .take_while(|arg| !arg.starts_with("--"))
+            .map(PathBuf::from)
+            .collect();
+        format.require_json()?;
+        let mut projects = ProjectPool::new(project_settings)?;
+        let results = markdown::twoslash_markdown(&mut projects, &paths, |project, result| {
+            format.to_json(project, result, position_encoding)
+        })?;
+        println!("{}", serde_json::to_string_pretty(&results)?);
     } else {
         // We are being asked to run in one-off mode.
         let source = {
@@ -97,7 +424,18 @@ fn main() -> Result<()> {
         };
         let project = Project::scaffold_with_code(project_settings, &source)?;
         let twoslash_result = project.twoslasher()?;
-        println!("{}", serde_json::to_string_pretty(&twoslash_result)?);
+        match format {
+            Format::Json | Format::Nodes => {
+                let json = format.to_json(&project, twoslash_result, position_encoding)?;
+                println!("{}", serde_json::to_string_pretty(&json)?);
+            }
+            Format::Html => println!("{}", html::document(&twoslash_result, tab_width)),
+            Format::Terminal => {
+                let color =
+                    std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+                print!("{}", terminal::render(&twoslash_result, color, tab_width));
+            }
+        }
     }
 
     Ok(())
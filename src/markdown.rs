@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::project::{Project, ProjectPool};
+use crate::twoslash::TwoSlash;
+
+/// A fenced code block in a markdown file.
+pub struct CodeBlock {
+    /// Which fenced block of the file this is, counting from 0
+    pub index: usize,
+    /// The line of the opening fence, counting from 0
+    pub line: u32,
+    /// The words of the info string after the opening fence, e.g. `rust` and `ignore`
+    pub attributes: Vec<String>,
+    /// The code between the fences, without the fence's indentation
+    pub code: String,
+    /// Where the block is in the file, from the start of the opening fence's line to the end of
+    /// the closing fence's line
+    pub range: Range<usize>,
+}
+
+impl CodeBlock {
+    pub fn has_attribute(&self, attribute: &str) -> bool {
+        self.attributes.iter().any(|it| it == attribute)
+    }
+
+    /// Whether the block is Rust code tagged `twoslash`.
+    pub fn is_twoslash(&self) -> bool {
+        (self.has_attribute("rust") || self.has_attribute("rs")) && self.has_attribute("twoslash")
+    }
+
+    /// Whether the block should be analyzed. `ignore`d blocks are often not valid Rust at all,
+    /// while `no_run` and `compile_fail` blocks are analyzed like any other; the latter are
+    /// expected to have errors.
+    pub fn should_twoslash(&self) -> bool {
+        self.is_twoslash() && !self.has_attribute("ignore")
+    }
+}
+
+/// An opening fence: its indentation, character and length.
+struct Fence {
+    indent: usize,
+    char: char,
+    len: usize,
+}
+
+/// Parses a line as a fence, returning the fence and the rest of the line.
+fn parse_fence(line: &str) -> Option<(Fence, &str)> {
+    let rest = line.trim_start_matches(' ');
+    let indent = line.len() - rest.len();
+    let char = rest.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = rest.len() - rest.trim_start_matches(char).len();
+    if indent > 3 || len < 3 {
+        return None;
+    }
+    Some((Fence { indent, char, len }, &rest[len..]))
+}
+
+/// Finds the fenced code blocks of a markdown file.
+pub fn code_blocks(markdown: &str) -> Vec<CodeBlock> {
+    let mut blocks = vec![];
+    let mut lines = markdown.split_inclusive('\n').enumerate();
+    let mut offset = 0;
+    while let Some((line, text)) = lines.next() {
+        let start = offset;
+        offset += text.len();
+        let Some((fence, info)) = parse_fence(text.trim_end()) else {
+            continue;
+        };
+        // Backticks can't appear in the info string of a backtick fence.
+        if fence.char == '`' && info.contains('`') {
+            continue;
+        }
+
+        let mut code = vec![];
+        for (_, text) in lines.by_ref() {
+            offset += text.len();
+            let text = text.trim_end_matches(['\n', '\r']);
+            let closes = parse_fence(text).is_some_and(|(closing, rest)| {
+                closing.char == fence.char && closing.len >= fence.len && rest.trim().is_empty()
+            });
+            if closes {
+                break;
+            }
+            let indent = text.len() - text.trim_start_matches(' ').len();
+            code.push(&text[indent.min(fence.indent)..]);
+        }
+
+        blocks.push(CodeBlock {
+            index: blocks.len(),
+            line: line as u32,
+            attributes: info
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|attribute| !attribute.is_empty())
+                .map(str::to_string)
+                .collect(),
+            code: code.join("\n"),
+            range: start..offset,
+        });
+    }
+    blocks
+}
+
+/// The markdown files at `path`: `path` itself if it is a file, otherwise the `.md` and `.mdx`
+/// files under it.
+pub fn markdown_files(path: &Path) -> Result<Vec<PathBuf>> {
+    find_files(path, &["md", "mdx"])
+}
+
+/// The files at `path`: `path` itself if it is a file, otherwise the files with one of
+/// `extensions` under it, skipping hidden directories, `node_modules` and `target`.
+pub fn find_files(path: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut entries = fs::read_dir(path)
+        .with_context(|| format!("could not read {}", path.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+
+    let mut files = vec![];
+    for entry in entries {
+        let name = entry.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with('.') || name == "node_modules" || name == "target" {
+            continue;
+        }
+        if entry.is_dir() {
+            files.extend(find_files(&entry, extensions)?);
+        } else if entry
+            .extension()
+            .is_some_and(|extension| extensions.iter().any(|it| extension == *it))
+        {
+            files.push(entry);
+        }
+    }
+    Ok(files)
+}
+
+/// Twoslashes the twoslash blocks of the markdown files at `paths`, keyed by file and then by
+/// the index of the block among the fenced blocks of its file. Each result is passed to `render`
+/// along with the project that analyzed it.
+pub fn twoslash_markdown<T>(
+    projects: &mut ProjectPool,
+    paths: &[PathBuf],
+    mut render: impl FnMut(&Project, TwoSlash) -> Result<T>,
+) -> Result<BTreeMap<String, BTreeMap<usize, T>>> {
+    let mut results = BTreeMap::new();
+    for path in paths {
+        for file in markdown_files(path)? {
+            let markdown = fs::read_to_string(&file)
+                .with_context(|| format!("could not read {}", file.display()))?;
+            let mut file_results = BTreeMap::new();
+            for block in code_blocks(&markdown)
+                .into_iter()
+                .filter(CodeBlock::should_twoslash)
+            {
+                let result = projects
+                    .project(&block.code)
+                    .and_then(|project| render(project, project.twoslasher()?))
+                    .with_context(|| {
+                        format!("{}:{}: could not twoslash", file.display(), block.line + 1)
+                    })?;
+                file_results.insert(block.index, result);
+            }
+            if !file_results.is_empty() {
+                results.insert(file.display().to_string(), file_results);
+            }
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::code_blocks;
+
+    #[test]
+    fn test_code_blocks() {
+        let markdown = "# Title
+
+```rust twoslash
+let x = 1;
+//  ^?
+```
+
+Some text.
+
+  ~~~~rs,ignore,twoslash
+  fn main() {
+      todo!()
+  }
+  ~~~~
+
+````md
+```rust
+nested
+```
+````
+
+```rust
+unclosed";
+        let blocks = code_blocks(markdown);
+        assert_eq!(blocks.len(), 4);
+
+        assert_eq!(blocks[0].line, 2);
+        assert_eq!(blocks[0].attributes, ["rust", "twoslash"]);
+        assert_eq!(blocks[0].code, "let x = 1;\n//  ^?");
+        assert_eq!(
+            &markdown[blocks[0].range.clone()],
+            "```rust twoslash\nlet x = 1;\n//  ^?\n```\n"
+        );
+        assert!(blocks[0].should_twoslash());
+
+        assert_eq!(blocks[1].attributes, ["rs", "ignore", "twoslash"]);
+        assert_eq!(blocks[1].code, "fn main() {\n    todo!()\n}");
+        assert!(blocks[1].is_twoslash());
+        assert!(!blocks[1].should_twoslash());
+
+        assert_eq!(blocks[2].code, "```rust\nnested\n```");
+        assert!(!blocks[2].is_twoslash());
+
+        assert_eq!(blocks[3].index, 3);
+        assert_eq!(blocks[3].code, "unclosed");
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::html;
+use crate::markdown::{code_blocks, CodeBlock};
+use crate::project::ProjectPool;
+
+/// Whether the preprocessor's output suits mdBook's `renderer`.
+pub fn supports(renderer: &str) -> bool {
+    renderer == "html"
+}
+
+/// Reads the `[context, book]` mdBook passes preprocessors, and returns the book with its
+/// twoslash blocks replaced by annotated HTML.
+pub fn preprocess(projects: &mut ProjectPool, input: &str, tab_width: u32) -> Result<String> {
+    // The book is edited as plain JSON, so that fields we don't know about survive.
+    let (context, mut book): (Value, Value) = serde_json::from_str(input)?;
+    // mdBook 0.5 renamed `sections` to `items`.
+    let key = if book.get("items").is_some() {
+        "items"
+    } else {
+        "sections"
+    };
+    let items = book
+        .get_mut(key)
+        .and_then(Value::as_array_mut)
+        .with_context(|| {
+            format!(
+                "the book from mdBook {} has no `items` or `sections` to preprocess",
+                context["mdbook_version"].as_str().unwrap_or("?")
+            )
+        })?;
+    preprocess_items(projects, items, tab_width)?;
+    Ok(serde_json::to_string(&book)?)
+}
+
+fn preprocess_items(projects: &mut ProjectPool, items: &mut [Value], tab_width: u32) -> Result<()> {
+    // Separators and part titles have nothing to render.
+    for chapter in items.iter_mut().filter_map(|item| item.get_mut("Chapter")) {
+        if let Some(content) = chapter.get("content").and_then(Value::as_str) {
+            let content = render_chapter(projects, content, tab_width)
+                .with_context(|| format!("in chapter {}", chapter["name"]))?;
+            chapter["content"] = content.into();
+        }
+        if let Some(sub_items) = chapter.get_mut("sub_items").and_then(Value::as_array_mut) {
+            preprocess_items(projects, sub_items, tab_width)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the twoslash blocks of a chapter with their HTML.
+pub fn render_chapter(projects: &mut ProjectPool, content: &str, tab_width: u32) -> Result<String> {
+    let blocks: Vec<_> = code_blocks(content)
+        .into_iter()
+        .filter(CodeBlock::should_twoslash)
+        .collect();
+    if blocks.is_empty() {
+        return Ok(content.to_string());
+    }
+
+    let mut rendered = String::new();
+    let mut pos = 0;
+    for (i, block) in blocks.iter().enumerate() {
+        rendered.push_str(&content[pos..block.range.start]);
+        if i == 0 {
+            rendered.push_str(html::STYLE);
+            rendered.push('\n');
+        }
+        let result = projects
+            .twoslash(&block.code)
+            .with_context(|| format!("could not twoslash the block on line {}", block.line + 1))?;
+        rendered.push_str(&html::render(&result, tab_width));
+        rendered.push('\n');
+        pos = block.range.end;
+    }
+    rendered.push_str(&content[pos..]);
+    Ok(rendered)
+}
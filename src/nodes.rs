@@ -0,0 +1,219 @@
+use serde::Serialize;
+
+use crate::twoslash::{
+    BootstrapError, CompletionEntry, DiagnosticCategory, PositionEncoder, PositionEncoding,
+    QueryKind, Tag, Timings, TwoSlash,
+};
+
+/// A twoslash result in the shape of the `twoslash` package from v0.2 on, which tools like
+/// `@shikijs/twoslash` consume: every annotation is a node of one flat list.
+#[derive(Serialize)]
+pub struct TwoslashNodes<'a> {
+    pub code: &'a str,
+    pub nodes: Vec<Node<'a>>,
+    pub meta: Meta<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Meta<'a> {
+    pub extension: &'a str,
+    /// Where the `^?` and `^=` queries point
+    pub position_queries: Vec<u32>,
+    /// Where the `^|` queries point
+    pub position_completions: Vec<u32>,
+    /// The start and end of each highlighted range
+    pub position_highlights: Vec<[u32; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootstrap_error: Option<&'a BootstrapError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<&'a Timings>,
+}
+
+/// An annotation of the code. `start` and `length` locate it in the code, and `line` and
+/// `character` are where it starts, both counting from 0.
+#[derive(Serialize)]
+pub struct Node<'a> {
+    pub start: u32,
+    pub length: u32,
+    pub line: u32,
+    pub character: u32,
+    #[serde(flatten)]
+    pub kind: NodeKind<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum NodeKind<'a> {
+    Hover {
+        target: &'a str,
+        text: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        docs: Option<&'a str>,
+    },
+    Query {
+        target: &'a str,
+        text: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        docs: Option<&'a str>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Completion {
+        completions: &'a [CompletionEntry],
+        completions_prefix: &'a str,
+    },
+    Error {
+        id: &'a str,
+        code: u32,
+        level: &'static str,
+        text: &'a str,
+    },
+    Highlight {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<&'a str>,
+    },
+    Tag {
+        name: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<&'a str>,
+    },
+}
+
+impl NodeKind<'_> {
+    /// Orders nodes starting at the same position the way `twoslash` does
+    fn order(&self) -> u8 {
+        match self {
+            NodeKind::Tag { .. } => 0,
+            NodeKind::Highlight { .. } => 1,
+            NodeKind::Hover { .. } => 2,
+            NodeKind::Query { .. } | NodeKind::Completion { .. } => 3,
+            NodeKind::Error { .. } => 4,
+        }
+    }
+}
+
+/// Converts a twoslash result with UTF-8 positions to nodes with `encoding` positions. `tag_line`
+/// says which line of the code a tag is on, as the result only knows the line of the snippet its
+/// directive was on.
+///
+/// Assists, adjustments and quick fixes have no node of their own and are left out.
+pub fn render<'a>(
+    result: &'a TwoSlash,
+    tag_line: impl Fn(&Tag) -> u32,
+    encoding: PositionEncoding,
+) -> TwoslashNodes<'a> {
+    let code = result.code.as_str();
+    let encoder = PositionEncoder::new(code, encoding);
+    let line_starts: Vec<u32> = std::iter::once(0)
+        .chain(code.match_indices('\n').map(|(i, _)| i as u32 + 1))
+        .collect();
+    let node = |mut start: u32, mut length: u32, kind: NodeKind<'a>| {
+        let line = line_starts.partition_point(|&line_start| line_start <= start) - 1;
+        let mut character = start - line_starts[line];
+        encoder.encode(&mut start, &mut length, &mut character);
+        Node {
+            start,
+            length,
+            line: line as u32,
+            character,
+            kind,
+        }
+    };
+    let target = |start: u32, length: u32| {
+        code.get(start as usize..(start + length) as usize)
+            .unwrap_or_default()
+    };
+
+    let mut nodes = vec![];
+    // Hovers spanning lines, like the one for the whole module, can't be shown on a token.
+    let single_line = |start: u32, length: u32| !target(start, length).contains('\n');
+    for info in result
+        .static_quick_infos
+        .iter()
+        .filter(|info| single_line(info.start, info.length))
+    {
+        nodes.push(node(
+            info.start,
+            info.length,
+            NodeKind::Hover {
+                target: &info.target_string,
+                text: &info.text,
+                docs: info.docs.as_deref(),
+            },
+        ));
+    }
+    for query in &result.queries {
+        let kind = match (&query.completions, &query.text) {
+            (Some(completions), _) => NodeKind::Completion {
+                completions,
+                completions_prefix: query.completions_prefix.as_deref().unwrap_or_default(),
+            },
+            (None, Some(text)) if query.kind != QueryKind::Assist => NodeKind::Query {
+                target: target(query.start, query.length),
+                text,
+                docs: query.docs.as_deref(),
+            },
+            _ => continue,
+        };
+        nodes.push(node(query.start, query.length, kind));
+    }
+    for error in &result.errors {
+        let level = match error.category {
+            DiagnosticCategory::Error => "error",
+            DiagnosticCategory::Warning => "warning",
+            DiagnosticCategory::Info => "suggestion",
+            DiagnosticCategory::Debug => "message",
+        };
+        let kind = NodeKind::Error {
+            id: &error.id,
+            code: error.code,
+            level,
+            text: &error.rendered_message,
+        };
+        nodes.push(node(error.start, error.length, kind));
+    }
+    for highlight in &result.highlights {
+        let kind = NodeKind::Highlight {
+            text: highlight.text.as_deref(),
+        };
+        nodes.push(node(highlight.start, highlight.length, kind));
+    }
+    for tag in &result.tags {
+        let line = (tag_line(tag) as usize).min(line_starts.len() - 1);
+        let kind = NodeKind::Tag {
+            name: &tag.name,
+            text: tag.annotation.as_deref(),
+        };
+        nodes.push(node(line_starts[line], 0, kind));
+    }
+    nodes.sort_by_key(|node| (node.start, node.kind.order()));
+
+    let starts = |completions: bool| {
+        nodes
+            .iter()
+            .filter(|node| match node.kind {
+                NodeKind::Query { .. } => !completions,
+                NodeKind::Completion { .. } => completions,
+                _ => false,
+            })
+            .map(|node| node.start)
+            .collect()
+    };
+    let meta = Meta {
+        extension: &result.extension,
+        position_queries: starts(false),
+        position_completions: starts(true),
+        position_highlights: result
+            .highlights
+            .iter()
+            .map(|highlight| {
+                let (mut start, mut length) = (highlight.start, highlight.length);
+                encoder.encode(&mut start, &mut length, &mut 0);
+                [start, start + length]
+            })
+            .collect(),
+        bootstrap_error: result.bootstrap_error.as_ref(),
+        timings: result.timings.as_ref(),
+    };
+    TwoslashNodes { code, nodes, meta }
+}
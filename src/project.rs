@@ -1,25 +1,51 @@
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::str::FromStr;
+use std::time::Instant;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
 
 use camino::Utf8PathBuf;
 use load_cargo::{load_workspace, LoadCargoConfig, ProcMacroServerChoice};
+use ra_cfg::{CfgAtom, CfgDiff};
+use ra_hir::{attach_db, ClosureStyle, DefWithBody};
 use ra_ide::{
-    Analysis, AnalysisHost, AssistResolveStrategy, CompletionConfig, CompletionFieldsToResolve,
-    Diagnostic, DiagnosticsConfig, FileId, FilePosition, HoverResult, LineCol, LineIndex,
-    StaticIndex, TextRange, TextSize, TokenId, TokenStaticData, VendoredLibrariesConfig,
+    AdjustmentHints, AdjustmentHintsMode, Analysis, AnalysisHost, AssistConfig,
+    AssistResolveStrategy, ClosureReturnTypeHints, CompletionConfig, CompletionFieldsToResolve,
+    Diagnostic, DiagnosticsConfig, DiscriminantHints, Edition, FileId, FilePosition, FileRange,
+    GenericParameterHints, HoverConfig, HoverDocFormat, HoverResult, InlayFieldsToResolve,
+    InlayHintPosition, InlayHintsConfig, InlayKind, LifetimeElisionHints, LineCol, LineIndex,
+    MemoryLayoutHoverConfig, MemoryLayoutHoverRenderKind, Semantics, StaticIndex, SubstTyLen,
+    TextEdit, TextRange, TextSize, TokenId, VendoredLibrariesConfig,
 };
+use ra_ide_db::assists::{ExprFillDefaultMode, SingleResolve};
+use ra_ide_db::base_db::salsa::Setter;
+use ra_ide_db::base_db::{BuiltCrateData, RootQueryDb};
+use ra_ide_db::defs::{Definition, IdentClass};
 use ra_ide_db::imports::insert_use::{ImportGranularity, InsertUseConfig, PrefixKind};
-use ra_ide_db::{ChangeWithProcMacros, MiniCore, SnippetCap};
-use ra_project_model::{CargoConfig, ProjectManifest, ProjectWorkspace, RustLibSource};
+use ra_ide_db::{ChangeWithProcMacros, MiniCore, RootDatabase, SnippetCap};
+use ra_intern::Symbol;
+use ra_proc_macro_api::ProcMacroClient;
+use ra_project_model::{
+    CargoConfig, CargoFeatures, CargoWorkspace, CfgOverrides, Metadata, ProjectManifest,
+    ProjectWorkspace, ProjectWorkspaceKind, RustLibSource, TargetDirectoryConfig,
+};
+use ra_syntax::ast::{self, HasName};
+use ra_syntax::{match_ast, AstNode, SyntaxKind, SyntaxNode, SyntaxToken};
 use ra_vfs::{AbsPathBuf, VfsPath};
+use regex::Regex;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
-use crate::query_parser::find_queries;
-use crate::twoslash::{CompletionEntry, Error, Query, QueryKind, StaticQuickInfo, TwoSlash};
+use crate::query_parser::{find_queries, Directive, MarkerKind};
+use crate::twoslash::{
+    Adjustment, AdjustmentKind, AdjustmentPosition, BootstrapError, CompletionEntry,
+    DiagnosticCategory, Error, Fix, Query, QueryKind, SourceEdit, StaticQuickInfo, Tag, Timings,
+    TwoSlash,
+};
 
 #[derive(Clone)]
 pub struct ProjectSettings<'a> {
@@ -29,6 +55,469 @@ pub struct ProjectSettings<'a> {
     pub cargo_toml: Option<&'a str>,
     /// Optional shared target directory for caching compiled deps
     pub target_dir: Option<&'a str>,
+    /// Optional directory to keep the lock file, target directory and resolved workspace of
+    /// projects in, so that later runs with the same manifest and toolchain don't resolve, compile
+    /// or run the build scripts of dependencies again
+    pub cache_dir: Option<&'a str>,
+    /// The edition of snippets without an `@edition` directive
+    pub edition: Edition,
+    /// The target triple of snippets without a `@target` directive, `None` for the host
+    pub target: Option<&'a str>,
+    /// What snippets without a `@crate-type` directive are compiled as
+    pub crate_type: CrateType,
+    /// Whether to report the adjustments, binding modes and drops the compiler inserts
+    pub adjustments: bool,
+    /// Whether to attach the available quick fixes to each error
+    pub fixes: bool,
+    /// What to include in static quick infos and `^?` queries
+    pub hover: HoverSettings,
+    /// Features and cfgs of snippets, which they can add to with directives
+    pub features: FeatureSettings,
+    /// Which proc-macro server expands proc macros, if any
+    pub proc_macro_server: ProcMacroServer,
+    /// Fail instead of analysing a project that cargo could not check
+    pub strict: bool,
+    /// Whether cargo may use the network, and where else it gets packages from
+    pub offline: OfflineSettings,
+    /// Report how long each phase took
+    pub timings: bool,
+    /// An existing workspace to analyse snippets in instead of a scratch package
+    pub workspace: Option<WorkspaceSettings<'a>>,
+    /// How many columns a tab takes up when lining up a marker's caret with the line above
+    pub tab_width: u32,
+}
+
+/// An existing workspace that snippets are analysed in as an example of one of its packages, so
+/// that they can use its crates, path dependencies and `[patch]` sections as they are.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkspaceSettings<'a> {
+    /// The directory of the workspace, or of one of its packages
+    pub root: &'a str,
+    /// The package snippets are an example of, by default the package at `root` or else the first
+    /// member of the workspace
+    pub package: Option<&'a str>,
+}
+
+/// The package of an existing workspace that snippets are an example of
+pub struct WorkspacePackage {
+    pub name: String,
+    /// The directory holding the package's manifest
+    pub dir: PathBuf,
+    pub edition: Edition,
+    /// The package's library, if it has one
+    pub lib: Option<LibTarget>,
+}
+
+/// The library target of a package.
+pub struct LibTarget {
+    /// The name of the crate, which code using the library refers to it by
+    pub name: String,
+    /// The root file of the crate
+    pub src_path: PathBuf,
+}
+
+impl WorkspaceSettings<'_> {
+    /// Asks cargo which package of the workspace snippets are an example of.
+    pub fn package(&self) -> Result<WorkspacePackage> {
+        let output = Command::new("cargo")
+            .args(["metadata", "--no-deps", "--format-version", "1"])
+            .current_dir(self.root)
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "could not read the workspace at {}:\n{}",
+                self.root,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        let members = metadata["workspace_members"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let packages: Vec<&serde_json::Value> = metadata["packages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|package| members.contains(&package["id"]))
+            .collect();
+
+        let root_manifest = Path::new(self.root).join("Cargo.toml").canonicalize()?;
+        let package = match self.package {
+            Some(name) => packages
+                .iter()
+                .find(|package| package["name"] == name)
+                .with_context(|| format!("the workspace has no package named `{}`", name))?,
+            None => packages
+                .iter()
+                .find(|package| {
+                    package["manifest_path"].as_str().map(Path::new) == Some(&root_manifest)
+                })
+                .or(packages.first())
+                .context("the workspace has no packages")?,
+        };
+        let manifest_path = package["manifest_path"]
+            .as_str()
+            .context("cargo did not say where the package is")?;
+        let lib = package["targets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|target| {
+                target["kind"].as_array().is_some_and(|kinds| {
+                    kinds.iter().any(|kind| {
+                        ["lib", "rlib", "dylib", "proc-macro"]
+                            .contains(&kind.as_str().unwrap_or(""))
+                    })
+                })
+            })
+            .and_then(|target| {
+                Some(LibTarget {
+                    name: target["name"].as_str()?.replace('-', "_"),
+                    src_path: PathBuf::from(target["src_path"].as_str()?),
+                })
+            });
+        Ok(WorkspacePackage {
+            name: package["name"].as_str().unwrap_or_default().to_string(),
+            dir: Path::new(manifest_path).parent().unwrap().to_path_buf(),
+            edition: package["edition"]
+                .as_str()
+                .unwrap_or_default()
+                .parse()
+                .unwrap_or(Edition::DEFAULT),
+            lib,
+        })
+    }
+}
+
+/// Controls the content of hovers. The default matches what `StaticIndex` produces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HoverSettings {
+    /// Show size, alignment, niches and field offsets
+    pub memory_layout: bool,
+    /// Show docs when hovering keywords
+    pub keywords: bool,
+    /// Show the doc comments of the hovered item
+    pub documentation: bool,
+    /// How many associated items of a trait to show, `None` for all of them
+    pub max_trait_assoc_items: Option<usize>,
+    /// How many fields of a struct, union or enum variant to show, `None` for all of them
+    pub max_fields: Option<usize>,
+    /// Show whether dropping a value of the type runs any code
+    pub drop_glue: bool,
+}
+
+impl Default for HoverSettings {
+    fn default() -> Self {
+        HoverSettings {
+            memory_layout: false,
+            keywords: true,
+            documentation: true,
+            max_trait_assoc_items: None,
+            max_fields: Some(5),
+            drop_glue: false,
+        }
+    }
+}
+
+impl HoverSettings {
+    /// Whether hovers of definitions keep the sections below their signature, which memory
+    /// layout and drop glue are shown in
+    fn render_extras(&self) -> bool {
+        self.memory_layout || self.drop_glue
+    }
+
+    fn config(&self) -> HoverConfig<'static> {
+        let memory_layout = self.memory_layout.then_some(MemoryLayoutHoverConfig {
+            size: Some(MemoryLayoutHoverRenderKind::Both),
+            offset: Some(MemoryLayoutHoverRenderKind::Both),
+            alignment: Some(MemoryLayoutHoverRenderKind::Both),
+            padding: Some(MemoryLayoutHoverRenderKind::Both),
+            niches: true,
+        });
+        HoverConfig {
+            links_in_hover: true,
+            memory_layout,
+            documentation: self.documentation,
+            keywords: self.keywords,
+            format: HoverDocFormat::Markdown,
+            max_trait_assoc_items_count: self.max_trait_assoc_items,
+            max_fields_count: self.max_fields,
+            max_enum_variants_count: Some(5),
+            max_subst_ty_len: SubstTyLen::Unlimited,
+            show_drop_glue: self.drop_glue,
+            minicore: MiniCore::default(),
+        }
+    }
+}
+
+/// Which features and cfgs the snippet is compiled with.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FeatureSettings {
+    /// Features to enable, e.g. `serde/derive` for a feature of a dependency
+    pub features: Vec<String>,
+    /// Enable every feature
+    pub all_features: bool,
+    /// Don't enable the `default` features
+    pub no_default_features: bool,
+    /// Extra `--cfg` flags, e.g. `docsrs` or `feature="nightly"`
+    pub cfgs: Vec<String>,
+}
+
+impl FeatureSettings {
+    /// Adds a cfg given as `name` or `key = "value"`.
+    pub fn add_cfg(&mut self, cfg: &str) -> Result<()> {
+        let captures = CFG_PARSER
+            .captures(cfg)
+            .ok_or_else(|| anyhow::anyhow!("invalid cfg: {:?}", cfg))?;
+        let cfg = match captures.name("value") {
+            Some(value) => format!("{}=\"{}\"", &captures["key"], value.as_str()),
+            None => captures["key"].to_string(),
+        };
+        if !self.cfgs.contains(&cfg) {
+            self.cfgs.push(cfg);
+        }
+        Ok(())
+    }
+
+    /// Adds features given as a comma or space separated list.
+    pub fn add_features(&mut self, features: &str) {
+        for feature in features.split([',', ' ']).filter(|f| !f.is_empty()) {
+            if !self.features.iter().any(|f| f == feature) {
+                self.features.push(feature.to_string());
+            }
+        }
+    }
+
+    fn cargo_features(&self) -> CargoFeatures {
+        if self.all_features {
+            CargoFeatures::All
+        } else {
+            CargoFeatures::Selected {
+                features: self.features.clone(),
+                no_default_features: self.no_default_features,
+            }
+        }
+    }
+
+    fn cargo_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if self.all_features {
+            args.push("--all-features".to_string());
+        } else if !self.features.is_empty() {
+            args.push("--features".to_string());
+            args.push(self.features.join(","));
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".to_string());
+        }
+        args
+    }
+
+    /// The flags to pass to rustc, in the format of `CARGO_ENCODED_RUSTFLAGS`. Cargo reads that
+    /// instead of `RUSTFLAGS`, so the flags already in either are kept, the way cargo reads them.
+    fn encoded_rustflags(&self) -> Option<String> {
+        if self.cfgs.is_empty() {
+            return None;
+        }
+        let mut flags: Vec<String> = match std::env::var("CARGO_ENCODED_RUSTFLAGS") {
+            Ok(flags) if flags.is_empty() => vec![],
+            Ok(flags) => flags.split('\x1f').map(str::to_string).collect(),
+            Err(_) => std::env::var("RUSTFLAGS")
+                .map(|flags| flags.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+        };
+        flags.extend(
+            self.cfgs
+                .iter()
+                .flat_map(|cfg| ["--cfg".to_string(), cfg.clone()]),
+        );
+        Some(flags.join("\x1f"))
+    }
+
+    fn cfg_overrides(&self) -> CfgOverrides {
+        let enable = self
+            .cfgs
+            .iter()
+            .map(|cfg| match cfg.split_once('=') {
+                Some((key, value)) => CfgAtom::KeyValue {
+                    key: Symbol::intern(key),
+                    value: Symbol::intern(value.trim_matches('"')),
+                },
+                None => CfgAtom::Flag(Symbol::intern(cfg)),
+            })
+            .collect();
+        CfgOverrides {
+            global: CfgDiff::new(enable, vec![]),
+            ..CfgOverrides::default()
+        }
+    }
+}
+
+lazy_static! {
+    /// Matches the ways cargo says it could not get a dependency
+    static ref UNRESOLVED_DEPENDENCY_PARSER: Regex = Regex::new(
+        r"(?:no matching package named|failed to get|failed to select a version for the requirement|failed to download) `(?P<name>[\w-]+)"
+    )
+    .unwrap();
+    /// Matches a dependency as a line of `[dependencies]` would have it: a package name, then a
+    /// version requirement or an inline table. A Cargo.toml cargo can't read fails the whole
+    /// workspace, so anything else is turned away before it gets there.
+    static ref DEPENDENCY_PARSER: Regex = Regex::new(
+        r#"^\s*(?P<name>[A-Za-z0-9_-]+)\s*=\s*(?P<spec>"[^"]*"|'[^']*'|\{[^{}]*\})\s*$"#
+    )
+    .unwrap();
+    static ref CFG_PARSER: Regex =
+        Regex::new(r#"^\s*(?P<key>[A-Za-z_]\w*)\s*(?:=\s*"(?P<value>[^"]*)"\s*)?$"#).unwrap();
+}
+
+/// How cargo gets the dependencies of snippets. The default fetches them from crates.io.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OfflineSettings {
+    /// Don't touch the network
+    pub offline: bool,
+    /// Don't touch the network or update the lock file, which then has to list every dependency
+    pub frozen: bool,
+    /// Where to get crates.io packages from instead
+    pub source: Option<LocalSource>,
+    /// Cargo.lock content to start from
+    pub cargo_lock: Option<String>,
+}
+
+/// A local replacement for crates.io.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalSource {
+    /// A directory made by `cargo vendor`
+    Vendor(PathBuf),
+    /// A local registry, e.g. made by `cargo local-registry`
+    Registry(PathBuf),
+}
+
+impl OfflineSettings {
+    fn cargo_args(&self) -> &'static [&'static str] {
+        match (self.frozen, self.offline) {
+            (true, _) => &["--frozen"],
+            (false, true) => &["--offline"],
+            (false, false) => &[],
+        }
+    }
+
+    /// The environment of the cargo commands rust-analyzer runs, which don't take our flags
+    fn extra_env(&self) -> Vec<(String, Option<String>)> {
+        match self.offline || self.frozen {
+            true => vec![("CARGO_NET_OFFLINE".to_string(), Some("true".to_string()))],
+            false => vec![],
+        }
+    }
+
+    /// The `.cargo/config.toml` that replaces crates.io with the local source
+    fn cargo_config(&self) -> Result<Option<String>> {
+        let (name, kind, path) = match &self.source {
+            None => return Ok(None),
+            Some(LocalSource::Vendor(path)) => ("vendored-sources", "directory", path),
+            Some(LocalSource::Registry(path)) => ("local-registry", "local-registry", path),
+        };
+        let path = std::path::absolute(path)?;
+        // A TOML literal string takes the path as it is, but can't hold quotes or line breaks.
+        let path = path
+            .to_str()
+            .filter(|path| !path.contains(['\'', '\n', '\r']))
+            .with_context(|| {
+                format!(
+                    "can't use {} as a local source: cargo needs a UTF-8 path without quotes or \
+                     line breaks",
+                    path.display()
+                )
+            })?;
+        Ok(Some(format!(
+            r#"[source.crates-io]
+replace-with = "{name}"
+
+[source.{name}]
+{kind} = '{path}'
+"#
+        )))
+    }
+}
+
+/// The proc-macro server to launch when loading the workspace.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ProcMacroServer {
+    /// Leave proc macros unexpanded
+    #[default]
+    None,
+    /// The server that comes with the toolchain
+    Sysroot,
+    Path(PathBuf),
+}
+
+impl FromStr for ProcMacroServer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(ProcMacroServer::None),
+            "sysroot" => Ok(ProcMacroServer::Sysroot),
+            path => Ok(ProcMacroServer::Path(PathBuf::from(path))),
+        }
+    }
+}
+
+impl ProcMacroServer {
+    fn choice(&self) -> Result<ProcMacroServerChoice> {
+        Ok(match self {
+            ProcMacroServer::None => ProcMacroServerChoice::None,
+            ProcMacroServer::Sysroot => ProcMacroServerChoice::Sysroot,
+            ProcMacroServer::Path(path) => {
+                let path = Utf8PathBuf::try_from(std::path::absolute(path)?)?;
+                ProcMacroServerChoice::Explicit(AbsPathBuf::assert(path))
+            }
+        })
+    }
+}
+
+/// The kind of target the snippet is compiled as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CrateType {
+    #[default]
+    Lib,
+    Bin,
+    /// A library compiled by `cargo test`, so with `cfg(test)`
+    Test,
+    Example,
+}
+
+impl FromStr for CrateType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lib" => Ok(CrateType::Lib),
+            "bin" => Ok(CrateType::Bin),
+            "test" => Ok(CrateType::Test),
+            "example" => Ok(CrateType::Example),
+            _ => Err(anyhow::anyhow!("invalid crate type: {:?}", s)),
+        }
+    }
+}
+
+impl CrateType {
+    /// Where the snippet goes in the package
+    fn path(self) -> &'static str {
+        match self {
+            CrateType::Lib | CrateType::Test => "src/lib.rs",
+            CrateType::Bin => "src/main.rs",
+            CrateType::Example => "examples/snippet.rs",
+        }
+    }
+
+    fn cargo_args(self) -> &'static [&'static str] {
+        match self {
+            CrateType::Lib | CrateType::Bin => &[],
+            CrateType::Test => &["--tests"],
+            CrateType::Example => &["--example", "snippet"],
+        }
+    }
 }
 
 struct Position {
@@ -38,112 +527,975 @@ struct Position {
     character: u32,
 }
 
+/// A query or assist marker of the snippet, and what it came to.
+pub struct Marker {
+    pub kind: QueryKind,
+    /// The line of the snippet, markers included, with the token the marker points at
+    pub line: u32,
+    /// Whether the token is in the part of the snippet that is shown
+    pub in_cut: bool,
+    /// Whether there is a line of code above the marker for it to point at. Markers without one
+    /// are on `line` themselves.
+    pub placed: bool,
+    /// `None` if rust-analyzer had nothing to say about the token, or it isn't shown
+    pub answer: Option<Query>,
+}
+
 pub struct Project {
     cut: Cut,
+    /// The line of the snippet each line of the code comes from
+    source_lines: Vec<u32>,
 
     host: Option<AnalysisHost>,
     analysis: Analysis,
-    queries: Vec<(QueryKind, TextSize)>,
+    queries: Vec<(MarkerKind, TextSize)>,
+    /// Markers with no line of code above them, and the lines of the snippet they are on
+    unplaced: Vec<(MarkerKind, u32)>,
 
     line_index: LineIndex,
-    token_to_ranges: HashMap<TokenId, Vec<TextRange>>,
-    token_data: Vec<(TokenId, TokenStaticData)>,
+    tokens: Vec<IndexedToken>,
 
     fid: FileId,
+    options: SnippetOptions,
+    /// The options of snippets without directives
+    default_options: SnippetOptions,
+    /// Problems setting up the workspace which make the analysis less accurate
+    warnings: Vec<String>,
+    /// The proc-macro server, which lives as long as the project so that it can keep expanding
+    /// macros as the code changes
+    _proc_macro: Option<ProcMacroClient>,
+    bootstrap_error: Option<BootstrapError>,
+    /// Why the workspace the snippet asks for couldn't be set up, when it is analyzed in another
+    /// one instead
+    workspace_error: Option<String>,
+    adjustments: bool,
+    fixes: bool,
+    hover: HoverSettings,
+    timings: bool,
+    /// How long setting up the analysis of the current snippet took
+    phase_timings: Timings,
+    tab_width: u32,
 }
 
 /// Result of bootstrapping a cargo project
 struct BootstrapResult {
     root: PathBuf,
-    lib_rs: PathBuf,
+    /// The file the snippet was written to
+    snippet_rs: PathBuf,
+    /// The target rust-analyzer should analyse for
+    target: Option<String>,
+    /// Where cargo puts build artifacts, if not in the project
+    target_dir: Option<PathBuf>,
+    /// Problems that make the analysis less accurate without stopping it
+    warnings: Vec<String>,
+    error: Option<BootstrapError>,
+    /// What rust-analyzer should take the workspace to be, instead of asking cargo
+    metadata: Option<Metadata>,
+    /// A file with the messages of a `cargo check` of the project, which rust-analyzer reads the
+    /// results of build scripts from instead of running them
+    build_script_output: Option<PathBuf>,
 }
 
 /// Generate default Cargo.toml content
-fn default_cargo_toml(project_name: &str) -> String {
+fn default_cargo_toml(project_name: &str, edition: Edition) -> String {
     format!(
         r#"[package]
-edition = "2021"
+edition = "{}"
 name = "{}"
 version = "0.0.0"
 "#,
-        project_name,
+        edition, project_name,
     )
 }
 
-/// Bootstraps a cargo project in a directory, and returns the paths of the
-/// project root and lib.rs.
+/// Bootstraps a cargo project in the settings' directory, and returns the paths of the project
+/// root and the file holding the snippet.
 fn bootstrap_project_in(
-    dir: &TempDir,
-    project_name: &str,
+    settings: &ProjectSettings,
     source: &str,
-    cargo_toml_template: Option<&str>,
-    target_dir: Option<&str>,
+    options: &SnippetOptions,
 ) -> Result<BootstrapResult> {
-    let root = dir.path();
-    let lib_rs = root.join("src/lib.rs");
+    let root = settings.tmpdir.path();
+    let snippet_rs = root.join(options.crate_type.path());
 
     // /root
+    // |- .cargo
+    //    |- config.toml (when there is a local source)
     // |- Cargo.toml
+    // |- Cargo.lock (when given or cached)
     // |- src
-    //    |- lib.rs
+    //    |- lib.rs or main.rs
+    // |- examples
+    //    |- snippet.rs
     let cargo_toml_path = root.join("Cargo.toml");
 
-    let cargo_content = match cargo_toml_template {
+    let cargo_content = match settings.cargo_toml {
         Some(template) => template.to_string(),
-        None => default_cargo_toml(project_name),
+        None => default_cargo_toml(settings.project_name, options.edition),
     };
+    let cargo_content = with_dependencies(cargo_content.trim(), &options.dependencies);
+    let cargo_config = settings.offline.cargo_config()?;
 
-    fs::write(cargo_toml_path, cargo_content.trim())?;
-    fs::create_dir(root.join("src"))?;
-    fs::write(lib_rs.clone(), source)?;
+    fs::write(&cargo_toml_path, cargo_content.trim())?;
+    if let Some(cargo_lock) = &settings.offline.cargo_lock {
+        fs::write(root.join("Cargo.lock"), cargo_lock)?;
+    }
+    if let Some(cargo_config) = &cargo_config {
+        fs::create_dir_all(root.join(".cargo"))?;
+        fs::write(root.join(".cargo/config.toml"), cargo_config)?;
+    }
+    fs::create_dir_all(snippet_rs.parent().unwrap())?;
+    fs::write(&snippet_rs, source)?;
 
     // Always run cargo check to fetch deps and set up sysroot for rust-analyzer
     let mut cmd = Command::new("cargo");
-    cmd.args(["check"]).current_dir(root);
+    cmd.args(["check"])
+        .args(options.crate_type.cargo_args())
+        .args(options.features.cargo_args())
+        .args(settings.offline.cargo_args())
+        .current_dir(root);
+    let rustflags = options.features.encoded_rustflags();
+    if let Some(rustflags) = &rustflags {
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags);
+    }
+
+    let mut warnings = vec![];
+    let target = check_for_target(&mut cmd, root, options.target.as_deref(), &mut warnings);
+
+    // Everything that decides what cargo resolves and builds, but not the snippet itself. Where
+    // the snippet goes decides the targets in the metadata, and the build script outputs point
+    // into the target directory.
+    let cache = match settings.cache_dir {
+        Some(cache_dir) => {
+            // A hash that stays the same across Rust releases, unlike the standard library's, so
+            // that the cache outlives the build of twoslash that filled it.
+            let rustc_version = rustc_version(root)?;
+            let args = format!("{:?}", cmd.get_args().collect::<Vec<_>>());
+            let parts = [
+                Some(RUST_ANALYZER_VERSION),
+                Some(rustc_version.as_str()),
+                Some(cargo_content.as_str()),
+                settings.offline.cargo_lock.as_deref(),
+                cargo_config.as_deref(),
+                Some(args.as_str()),
+                rustflags.as_deref(),
+                Some(options.crate_type.path()),
+                settings.target_dir,
+            ];
+            let mut hasher = Sha256::new();
+            for part in parts {
+                match part {
+                    Some(part) => {
+                        hasher.update([1]);
+                        hasher.update((part.len() as u64).to_le_bytes());
+                        hasher.update(part);
+                    }
+                    None => hasher.update([0]),
+                }
+            }
+            let key: String = hasher.finalize()[..8]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            Some(Path::new(cache_dir).join(key))
+        }
+        None => None,
+    };
+    if let Some(cache) = &cache {
+        fs::create_dir_all(cache)?;
+    }
 
     // Use shared target directory if provided (caches compiled deps across runs)
-    if let Some(target) = target_dir {
-        fs::create_dir_all(target)?;
-        cmd.arg("--target-dir").arg(target);
+    let target_dir = match (settings.target_dir, &cache) {
+        (Some(target_dir), _) => Some(PathBuf::from(target_dir)),
+        (None, Some(cache)) => Some(cache.join("target")),
+        (None, None) => None,
+    };
+    if let Some(target_dir) = &target_dir {
+        fs::create_dir_all(target_dir)?;
+        cmd.arg("--target-dir").arg(target_dir);
+    }
+
+    // A complete cache entry means cargo has already fetched and checked the dependencies, and
+    // resolved the workspace rust-analyzer loads, so that we don't run cargo again.
+    let entry = cache.as_deref().map(CacheEntry::new);
+    if let Some(entry) = entry.as_ref().filter(|entry| entry.lock.exists()) {
+        fs::copy(&entry.lock, root.join("Cargo.lock"))?;
+        let metadata = read_cached(&entry.metadata, root)?;
+        let build_script_output = root.join("build-scripts.json");
+        fs::write(&build_script_output, read_cached(&entry.messages, root)?)?;
+        return Ok(BootstrapResult {
+            root: root.to_path_buf(),
+            snippet_rs,
+            target,
+            target_dir,
+            warnings,
+            error: None,
+            metadata: Some(serde_json::from_str(&metadata)?),
+            build_script_output: Some(build_script_output),
+        });
     }
 
-    cmd.output()?;
+    let output = cmd.arg("--message-format=json").output()?;
+    let error = match output.status.success() {
+        true => None,
+        false => bootstrap_error(&output, Some(&cargo_toml_path)),
+    };
+
+    // Only cache what worked. Files are written to the side first, as other processes may be
+    // reading the cache, and the lock file goes last, as it marks the entry complete.
+    if let Some(entry) = entry.filter(|_| error.is_none()) {
+        let mut metadata = Command::new("cargo");
+        metadata
+            .args(["metadata", "--format-version", "1"])
+            .args(options.features.cargo_args())
+            .args(settings.offline.cargo_args())
+            .current_dir(root);
+        if let Some(target) = &target {
+            metadata.args(["--filter-platform", target]);
+        }
+        if let Some(rustflags) = &rustflags {
+            metadata.env("CARGO_ENCODED_RUSTFLAGS", rustflags);
+        }
+        let metadata = metadata.output()?;
+        if metadata.status.success() {
+            write_cached(&entry.metadata, &metadata.stdout, root)?;
+            write_cached(&entry.messages, &output.stdout, root)?;
+            write_cached(&entry.lock, &fs::read(root.join("Cargo.lock"))?, root)?;
+        }
+    }
 
     Ok(BootstrapResult {
         root: root.to_path_buf(),
-        lib_rs,
+        snippet_rs,
+        target,
+        target_dir,
+        warnings,
+        error,
+        metadata: None,
+        build_script_output: None,
+    })
+}
+
+/// Sets up the snippet as an example of `package` of an existing workspace, without touching
+/// the workspace: the snippet is written to the settings' directory, and is an example only in
+/// the metadata rust-analyzer is given. Cargo checks the package first, so that problems with it
+/// or its dependencies are reported the way they are for other snippets.
+fn bootstrap_workspace(
+    settings: &ProjectSettings,
+    workspace: &WorkspaceSettings,
+    package: &WorkspacePackage,
+    source: &str,
+    options: &SnippetOptions,
+) -> Result<BootstrapResult> {
+    let root = Path::new(workspace.root).canonicalize()?;
+    let snippet_rs = settings.tmpdir.path().join("twoslash_snippet.rs");
+    fs::write(&snippet_rs, source)?;
+
+    let mut warnings = vec![];
+    if !options.dependencies.is_empty() {
+        warnings.push(format!(
+            "dependencies can't be added to an existing workspace, so `@dependency` directives \
+             are ignored; add them to `{}` instead",
+            package.name
+        ));
+    }
+    if options.crate_type != CrateType::Example {
+        warnings.push(
+            "snippets in an existing workspace are examples, so the `@crate-type` directive is \
+             ignored"
+                .to_string(),
+        );
+    }
+
+    let cargo = |command: &str| {
+        let mut cmd = Command::new("cargo");
+        cmd.arg(command)
+            .args(options.features.cargo_args())
+            .args(settings.offline.cargo_args())
+            .current_dir(&root);
+        if let Some(rustflags) = options.features.encoded_rustflags() {
+            cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags);
+        }
+        cmd
+    };
+
+    let mut check = cargo("check");
+    check.args(["--package", &package.name, "--message-format=json"]);
+    let target = check_for_target(&mut check, &root, options.target.as_deref(), &mut warnings);
+    if let Some(target_dir) = settings.target_dir {
+        check.arg("--target-dir").arg(target_dir);
+    }
+    let output = check.output()?;
+    let error = match output.status.success() {
+        true => None,
+        false => bootstrap_error(&output, None),
+    };
+
+    let mut metadata = cargo("metadata");
+    metadata.args(["--format-version", "1"]);
+    if let Some(target) = &target {
+        metadata.args(["--filter-platform", target]);
+    }
+    let output = metadata.output()?;
+    if !output.status.success() {
+        return Err(match bootstrap_error(&output, None) {
+            Some(error) => error.into(),
+            None => anyhow::anyhow!("could not read the workspace at {}", root.display()),
+        });
+    }
+    let mut metadata: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let members = metadata["workspace_members"].clone();
+    let targets = metadata["packages"]
+        .as_array_mut()
+        .into_iter()
+        .flatten()
+        .find(|it| {
+            it["name"] == package.name.as_str()
+                && members
+                    .as_array()
+                    .is_some_and(|members| members.contains(&it["id"]))
+        })
+        .and_then(|it| it["targets"].as_array_mut())
+        .with_context(|| format!("cargo did not list the targets of `{}`", package.name))?;
+    targets.push(serde_json::json!({
+        "name": "twoslash_snippet",
+        "kind": ["example"],
+        "crate_types": ["bin"],
+        "required-features": [],
+        "src_path": snippet_rs,
+        "edition": package.edition.to_string(),
+        "doctest": false,
+        "test": false,
+        "doc": false,
+    }));
+
+    Ok(BootstrapResult {
+        root,
+        snippet_rs,
+        target,
+        target_dir: settings.target_dir.map(PathBuf::from),
+        warnings,
+        error,
+        metadata: Some(serde_json::from_value(metadata)?),
+        build_script_output: None,
     })
 }
 
+/// What `--cache-dir` keeps for a project, besides its target directory
+struct CacheEntry {
+    lock: PathBuf,
+    /// The output of `cargo metadata`
+    metadata: PathBuf,
+    /// The output of `cargo check --message-format=json`
+    messages: PathBuf,
+}
+
+impl CacheEntry {
+    fn new(cache: &Path) -> Self {
+        CacheEntry {
+            lock: cache.join("Cargo.lock"),
+            metadata: cache.join("metadata.json"),
+            messages: cache.join("messages.json"),
+        }
+    }
+}
+
+/// Stands for the project root in cached cargo output, as every snippet gets a new one.
+const CACHED_ROOT: &str = "{twoslash-root}";
+
+/// How `root` is written in cargo's JSON output
+fn json_path(root: &Path) -> Result<String> {
+    let path = serde_json::to_string(root)?;
+    Ok(path[1..path.len() - 1].to_string())
+}
+
+/// Writes cargo output for `root` to the cache, via a file only this process writes to.
+fn write_cached(path: &Path, content: &[u8], root: &Path) -> Result<()> {
+    let content = String::from_utf8_lossy(content).replace(&json_path(root)?, CACHED_ROOT);
+    let partial = path.with_extension(format!("{}", std::process::id()));
+    fs::write(&partial, content)?;
+    fs::rename(partial, path)?;
+    Ok(())
+}
+
+/// Reads cached cargo output as if it were for `root`.
+fn read_cached(path: &Path, root: &Path) -> Result<String> {
+    Ok(fs::read_to_string(path)?.replace(CACHED_ROOT, &json_path(root)?))
+}
+
+/// The version of the `ra_ap_*` crates, which decides what rust-analyzer needs from cargo. Keep
+/// it in sync with Cargo.toml.
+const RUST_ANALYZER_VERSION: &str = "0.0.315";
+
+/// The full version of the rustc that checks the snippets in `root`
+fn rustc_version(root: &Path) -> Result<String> {
+    let output = Command::new("rustc")
+        .arg("-vV")
+        .current_dir(root)
+        .output()?;
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Collects the errors of a failed `cargo check`, other than those in the snippet itself. Returns
+/// `None` if the snippet is all that failed.
+fn bootstrap_error(output: &Output, cargo_toml_path: Option<&Path>) -> Option<BootstrapError> {
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+    // Errors in dependencies. The snippet's own have a different manifest.
+    let mut messages: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| {
+            message["reason"] == "compiler-message"
+                && message["message"]["level"] == "error"
+                && message["manifest_path"].as_str().map(Path::new) != cargo_toml_path
+        })
+        .filter_map(|message| Some(message["message"]["rendered"].as_str()?.trim().to_string()))
+        .collect();
+
+    // Errors from cargo itself, which start unindented and run until the next message or status
+    // line. Which crates failed to compile is already clear from their errors.
+    let mut message: Option<String> = None;
+    for line in stderr.lines() {
+        let starts_message = line.starts_with("error") || line.starts_with("warning");
+        // Status lines are right-aligned to 12 columns, e.g. "    Checking foo v0.1.0"
+        let is_status = line.starts_with(' ')
+            && line.get(12..13) == Some(" ")
+            && line[..12].trim_start().chars().all(char::is_alphabetic);
+        if starts_message || is_status {
+            messages.extend(message.take());
+        }
+        if line.starts_with("error") {
+            message = Some(line.to_string());
+        } else if let Some(message) = message.as_mut().filter(|_| !is_status) {
+            message.push('\n');
+            message.push_str(line);
+        }
+    }
+    messages.extend(message);
+    messages.retain(|message| !message.starts_with("error: could not compile"));
+
+    if messages.is_empty() {
+        return None;
+    }
+    let messages: Vec<String> = messages
+        .into_iter()
+        .map(|message| message.trim().to_string())
+        .collect();
+    let mut unresolved_dependencies = vec![];
+    for message in &messages {
+        for captures in UNRESOLVED_DEPENDENCY_PARSER.captures_iter(message) {
+            let name = captures["name"].to_string();
+            if !unresolved_dependencies.contains(&name) {
+                unresolved_dependencies.push(name);
+            }
+        }
+    }
+    Some(BootstrapError {
+        messages,
+        unresolved_dependencies,
+        stderr,
+    })
+}
+
+/// Has `cmd` check for `target`, and returns the target to analyse for.
+///
+/// Without the target's standard library cargo can't check anything, so we check for the host
+/// instead, which still fetches the dependencies and runs their build scripts. rust-analyzer only
+/// needs the target's cfgs, which rustc knows about either way. Unknown targets are analysed for
+/// the host.
+fn check_for_target(
+    cmd: &mut Command,
+    root: &Path,
+    target: Option<&str>,
+    warnings: &mut Vec<String>,
+) -> Option<String> {
+    let triple = target?;
+    match target_libdir(root, triple) {
+        Some(libdir) if libdir.exists() => {
+            cmd.arg("--target").arg(triple);
+        }
+        Some(_) => warnings.push(format!(
+            "the standard library for `{}` is not installed, so dependencies were checked for \
+             the host; run `rustup target add {}` for complete results",
+            triple, triple
+        )),
+        None => {
+            warnings.push(format!(
+                "unknown target `{}`, analysing for the host",
+                triple
+            ));
+            return None;
+        }
+    }
+    Some(triple.to_string())
+}
+
+/// Where rustc looks for the standard library of `target`, or `None` if it doesn't know `target`.
+fn target_libdir(root: &Path, target: &str) -> Option<PathBuf> {
+    let output = Command::new("rustc")
+        .args(["--print", "target-libdir", "--target", target])
+        .current_dir(root)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(PathBuf::from(String::from_utf8(output.stdout).ok()?.trim()))
+}
+
+/// The tokens of the snippet that refer to the same definition, and the hover they share.
+struct IndexedToken {
+    ranges: Vec<TextRange>,
+    hover: Option<HoverResult>,
+}
+
+/// Finds the tokens of the snippet that refer to something, and their hovers.
+///
+/// Unlike `StaticIndex`, this only looks at the snippet file: hovering everything in the
+/// workspace, dependencies included, would be thrown away straight after.
 fn pre_index(
+    host: Option<&AnalysisHost>,
     analysis: &Analysis,
     fid: FileId,
     source: &str,
-) -> (
-    HashMap<TokenId, Vec<TextRange>>,
-    Vec<(TokenId, TokenStaticData)>,
-    LineIndex,
-    Cut,
-) {
-    let si = StaticIndex::compute(&analysis, VendoredLibrariesConfig::Excluded);
-
-    let mut token_to_ranges = HashMap::<TokenId, Vec<TextRange>>::default();
-    for (range, id) in si
-        .files
-        .iter()
-        .find(|fi| fi.file_id == fid)
-        .unwrap()
-        .tokens
-        .iter()
+    hover: &HoverSettings,
+) -> (Vec<IndexedToken>, LineIndex, Cut) {
+    let (mut tokens, rehover) = match host {
+        Some(host) => {
+            let db = host.raw_database();
+            (attach_db(db, || index_tokens(db, fid, hover)), false)
+        }
+        // A standalone file is all there is to index, but `StaticIndex` always uses its own
+        // hover config, so redo the hovers if we were asked for something else.
+        None => (
+            static_index_tokens(analysis, fid),
+            *hover != HoverSettings::default(),
+        ),
+    };
+
+    let config = hover.config();
+    for token in tokens
+        .iter_mut()
+        .filter(|token| rehover || token.hover.is_none())
     {
-        token_to_ranges.entry(*id).or_default().push(*range);
+        // Only take a hover about the token itself; e.g. the module token spans the whole file,
+        // but hovering its start would describe the first keyword instead.
+        let info = token.ranges.iter().find_map(|range| {
+            let file_range = FileRange {
+                file_id: fid,
+                range: TextRange::empty(range.start()),
+            };
+            match analysis.hover(&config, file_range) {
+                Ok(Some(info)) if info.range == *range => Some(info.info),
+                _ => None,
+            }
+        });
+        if info.is_some() {
+            token.hover = match hover.render_extras() {
+                true => info,
+                false => info.map(without_extras),
+            };
+        }
     }
-    let token_data = si.tokens.iter().collect();
 
     let line_index = LineIndex::new(source);
     let cut = Cut::new(source, &line_index);
 
-    (token_to_ranges, token_data, line_index, cut)
+    (tokens, line_index, cut)
+}
+
+/// Takes out the sections rust-analyzer adds below the signature when a definition itself is
+/// hovered, e.g. its notable traits or whether it's dyn-compatible, which `StaticIndex` leaves
+/// out. Without memory layout and drop glue, each of them is a single line.
+fn without_extras(mut info: HoverResult) -> HoverResult {
+    const EXTRAS: [&str; 7] = [
+        "Implements notable traits: ",
+        "Is dyn-compatible",
+        "Is not dyn-compatible",
+        "covariant",
+        "contravariant",
+        "invariant",
+        "bivariant",
+    ];
+    // Rewriting the links of the markdown also turns its `___` rules into `---`
+    const RULE: &str = "\n\n---\n\n";
+    let markup = info.markup.as_str();
+    let Some(start) = markup.find(RULE) else {
+        return info;
+    };
+    // The extras come straight after the signature, before the docs
+    let mut rest = &markup[start..];
+    while let Some(section) = rest.strip_prefix(RULE) {
+        let end = section.find('\n').unwrap_or(section.len());
+        if !EXTRAS.iter().any(|extra| section[..end].starts_with(extra)) {
+            break;
+        }
+        rest = &section[end..];
+    }
+    info.markup = format!("{}{}", &markup[..start], rest).into();
+    info
+}
+
+/// Groups the tokens of the file by the definitions they refer to, in order of first use. Only
+/// the module, which spans the whole file and can't be hovered, gets its hover here.
+fn index_tokens(db: &RootDatabase, fid: FileId, hover: &HoverSettings) -> Vec<IndexedToken> {
+    let sema = Semantics::new(db);
+    let root = sema.parse_guess_edition(fid).syntax().clone();
+    let mut tokens = vec![];
+    let mut definitions = HashMap::<Definition, usize>::new();
+
+    if let Some(module) = sema.file_to_module_def(fid) {
+        let definition = Definition::Module(module);
+        let display_target = module.krate(db).to_display_target(db);
+        let mut markup = format!("```rust\n{}\n```", definition.label(db, display_target));
+        if let Some(docs) = definition
+            .docs(db, None, display_target)
+            .filter(|_| hover.documentation)
+        {
+            markup.push_str(&format!("\n___\n\n{}", docs.as_str()));
+        }
+        definitions.insert(definition, tokens.len());
+        tokens.push(IndexedToken {
+            ranges: vec![root.text_range()],
+            hover: Some(HoverResult {
+                markup: markup.into(),
+                actions: vec![],
+            }),
+        });
+    }
+
+    for token in root
+        .descendants_with_tokens()
+        .filter_map(|it| it.into_token())
+    {
+        let Some(defs) = sema
+            .descend_into_macros_exact(token.clone())
+            .into_iter()
+            .find_map(|token| {
+                IdentClass::classify_token(&sema, &token)
+                    .map(IdentClass::definitions)
+                    .filter(|defs| !defs.is_empty())
+            })
+        else {
+            continue;
+        };
+        for (definition, _) in defs {
+            let index = *definitions.entry(definition).or_insert_with(|| {
+                tokens.push(IndexedToken {
+                    ranges: vec![],
+                    hover: None,
+                });
+                tokens.len() - 1
+            });
+            tokens[index].ranges.push(token.text_range());
+        }
+    }
+    tokens
+}
+
+/// Indexes a standalone file, for which we have no database to walk ourselves.
+fn static_index_tokens(analysis: &Analysis, fid: FileId) -> Vec<IndexedToken> {
+    let si = StaticIndex::compute(analysis, VendoredLibrariesConfig::Excluded);
+    let mut ranges = HashMap::<TokenId, Vec<TextRange>>::default();
+    if let Some(file) = si.files.iter().find(|file| file.file_id == fid) {
+        for (range, id) in &file.tokens {
+            ranges.entry(*id).or_default().push(*range);
+        }
+    }
+    si.tokens
+        .iter()
+        .filter_map(|(id, data)| {
+            Some(IndexedToken {
+                ranges: ranges.remove(&id)?,
+                hover: data.hover.clone(),
+            })
+        })
+        .collect()
+}
+
+/// What a snippet asks for with `// @name: value` directives, on top of the project settings.
+#[derive(Clone)]
+struct SnippetOptions {
+    edition: Edition,
+    /// The target triple to analyse for, `None` for the host
+    target: Option<String>,
+    crate_type: CrateType,
+    /// Dependencies to add to the manifest, as `name = spec` lines sorted by name
+    dependencies: Vec<String>,
+    features: FeatureSettings,
+    /// Directives we don't know about, which are passed through as tags
+    tags: Vec<Tag>,
+    /// Directives we know about but whose value we could not understand
+    errors: Vec<String>,
+}
+
+impl SnippetOptions {
+    /// The options of snippets without directives
+    fn defaults(settings: &ProjectSettings) -> Self {
+        SnippetOptions {
+            edition: settings.edition,
+            target: settings.target.map(str::to_string),
+            crate_type: settings.crate_type,
+            dependencies: vec![],
+            features: settings.features.clone(),
+            tags: vec![],
+            errors: vec![],
+        }
+    }
+
+    fn new(directives: Vec<Directive>, defaults: &SnippetOptions) -> Self {
+        let mut options = defaults.clone();
+        for Directive { name, value, line } in directives {
+            match (name.as_str(), value) {
+                ("edition", Some(value)) => match value.parse() {
+                    Ok(edition) => options.edition = edition,
+                    Err(err) => options.errors.push(err.to_string()),
+                },
+                ("edition", None) => options.errors.push("missing edition".to_string()),
+                ("target", Some(value)) => options.target = Some(value),
+                ("crate-type", Some(value)) => match value.parse() {
+                    Ok(crate_type) => options.crate_type = crate_type,
+                    Err(err) => options.errors.push(err.to_string()),
+                },
+                ("dependency", Some(value)) => match DEPENDENCY_PARSER.captures(&value) {
+                    Some(capture) => options
+                        .dependencies
+                        .push(format!("{} = {}", &capture["name"], &capture["spec"])),
+                    None => options
+                        .errors
+                        .push(format!("invalid dependency: {:?}", value)),
+                },
+                ("dependency", None) => options.errors.push("missing dependency".to_string()),
+                ("features", Some(value)) => options.features.add_features(&value),
+                ("all-features", None) => options.features.all_features = true,
+                ("no-default-features", None) => options.features.no_default_features = true,
+                ("cfg", Some(value)) => {
+                    if let Err(err) = options.features.add_cfg(&value) {
+                        options.errors.push(err.to_string());
+                    }
+                }
+                ("features" | "cfg" | "target" | "crate-type", None) => {
+                    options.errors.push(format!("missing {}", name))
+                }
+                ("all-features" | "no-default-features", Some(_)) => options
+                    .errors
+                    .push(format!("`@{}` doesn't take a value", name)),
+                (_, annotation) => options.tags.push(Tag {
+                    name,
+                    line,
+                    annotation,
+                }),
+            }
+        }
+        options.dependencies.sort();
+        options.dependencies.dedup();
+        options
+    }
+}
+
+/// What a snippet needs from its workspace. Snippets with the same key can share a workspace.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WorkspaceKey {
+    target: Option<String>,
+    crate_type: CrateType,
+    dependencies: Vec<String>,
+    features: FeatureSettings,
+}
+
+impl WorkspaceKey {
+    pub fn new(settings: &ProjectSettings, source: &str) -> Self {
+        let options = SnippetOptions::new(
+            find_queries(source, settings.tab_width).directives,
+            &SnippetOptions::defaults(settings),
+        );
+        WorkspaceKey {
+            target: options.target,
+            crate_type: options.crate_type,
+            dependencies: options.dependencies,
+            features: options.features,
+        }
+    }
+}
+
+/// How many projects a pool keeps warm. Each holds a whole rust-analyzer database, so the least
+/// recently used one makes way for a new one.
+const MAX_POOLED_PROJECTS: usize = 8;
+
+/// Projects kept warm between snippets, one per workspace the snippets need.
+pub struct ProjectPool<'a> {
+    settings: ProjectSettings<'a>,
+    /// The projects, least recently used first
+    projects: Vec<PooledProject>,
+}
+
+struct PooledProject {
+    key: WorkspaceKey,
+    project: Project,
+    /// The directory of the workspace, when the pool made it. Dropped after the project.
+    _dir: Option<TempDir>,
+}
+
+impl<'a> ProjectPool<'a> {
+    /// Scaffolds the project for snippets without directives up front, so that the first snippet
+    /// doesn't pay for it.
+    pub fn new(settings: ProjectSettings<'a>) -> Result<Self> {
+        let project = PooledProject {
+            key: WorkspaceKey::new(&settings, ""),
+            project: Project::scaffold(settings.clone())?,
+            _dir: None,
+        };
+        Ok(ProjectPool {
+            settings,
+            projects: vec![project],
+        })
+    }
+
+    pub fn twoslash(&mut self, source: &str) -> Result<TwoSlash> {
+        self.project(source)?.twoslasher()
+    }
+
+    /// The project for `source`, updated to analyze it.
+    ///
+    /// A workspace that can't be set up, e.g. because of a dependency cargo can't read, leaves
+    /// the snippet to the project for snippets without directives, which reports why. With
+    /// `strict` the error is returned instead.
+    pub fn project(&mut self, source: &str) -> Result<&Project> {
+        let key = WorkspaceKey::new(&self.settings, source);
+        let pooled = match self.take(&key, source) {
+            Some(pooled) => pooled,
+            None => match self.scaffold(key.clone(), source) {
+                Ok(pooled) => pooled,
+                Err(error) if !self.settings.strict => {
+                    let key = WorkspaceKey::new(&self.settings, "");
+                    let mut pooled = match self.take(&key, source) {
+                        Some(pooled) => pooled,
+                        None => {
+                            let pooled = self.scaffold(key, "")?;
+                            PooledProject {
+                                project: pooled.project.apply_change(source.to_string()),
+                                ..pooled
+                            }
+                        }
+                    };
+                    pooled.project.workspace_error = Some(format!("{:#}", error));
+                    pooled
+                }
+                Err(error) => return Err(error),
+            },
+        };
+        if self.projects.len() == MAX_POOLED_PROJECTS {
+            self.projects.remove(0);
+        }
+        self.projects.push(pooled);
+        Ok(&self.projects.last().unwrap().project)
+    }
+
+    /// Takes the project with `key` out of the pool, updated to analyze `source`.
+    fn take(&mut self, key: &WorkspaceKey, source: &str) -> Option<PooledProject> {
+        let i = self.projects.iter().position(|pooled| pooled.key == *key)?;
+        let pooled = self.projects.remove(i);
+        Some(PooledProject {
+            project: pooled.project.apply_change(source.to_string()),
+            ..pooled
+        })
+    }
+
+    /// Makes a workspace of its own for `source`.
+    fn scaffold(&self, key: WorkspaceKey, source: &str) -> Result<PooledProject> {
+        let dir = TempDir::new()?;
+        let project = Project::scaffold_with_code(
+            ProjectSettings {
+                tmpdir: &dir,
+                ..self.settings.clone()
+            },
+            source,
+        )?;
+        Ok(PooledProject {
+            key,
+            project,
+            _dir: Some(dir),
+        })
+    }
+}
+
+/// Adds `dependencies` to the `[dependencies]` table of `manifest`, creating it if needed. A
+/// dependency the manifest already has is replaced, as cargo doesn't take a key twice.
+fn with_dependencies(manifest: &str, dependencies: &[String]) -> String {
+    if dependencies.is_empty() {
+        return manifest.to_string();
+    }
+    // `name = ...`, `name.workspace = true` and `"name" = ...` all declare `name`.
+    fn name(key: &str) -> &str {
+        key.split('.')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_matches('"')
+    }
+    let names: Vec<&str> = dependencies
+        .iter()
+        .filter_map(|dependency| dependency.split_once('='))
+        .map(|(key, _)| name(key))
+        .collect();
+    let mut table = None;
+    let mut lines: Vec<&str> = vec![];
+    for line in manifest.lines() {
+        if line.trim().starts_with('[') {
+            table = table_name(line);
+        }
+        let replaced = match table.as_deref() {
+            Some("dependencies") => line
+                .split_once('=')
+                .is_some_and(|(key, _)| names.contains(&name(key))),
+            // A `[dependencies.name]` table
+            Some(table) => table
+                .strip_prefix("dependencies.")
+                .is_some_and(|key| names.contains(&name(key))),
+            None => false,
+        };
+        if !replaced {
+            lines.push(line);
+        }
+    }
+
+    let dependencies = dependencies.join("\n");
+    match lines
+        .iter()
+        .position(|line| table_name(line).as_deref() == Some("dependencies"))
+    {
+        Some(i) => lines.insert(i + 1, &dependencies),
+        None => {
+            lines.push("");
+            lines.push("[dependencies]");
+            lines.push(&dependencies);
+        }
+    }
+    lines.join("\n")
+}
+
+/// The name of the table a manifest line opens, e.g. `dependencies.serde` for
+/// `[ dependencies . serde ] # comment`, or `None` if it isn't a table header.
+fn table_name(line: &str) -> Option<String> {
+    let header = line.split('#').next().unwrap_or_default().trim();
+    let name = header.strip_prefix('[')?.strip_suffix(']')?;
+    Some(name.split('.').map(str::trim).collect::<Vec<_>>().join("."))
+}
+
+/// Sets the edition of the crates `fid` belongs to, leaving them alone if they already have it.
+fn set_edition(host: &mut AnalysisHost, fid: FileId, edition: Edition) {
+    let db = host.raw_database_mut();
+    for krate in db.relevant_crates(fid).iter() {
+        let data = krate.data(db);
+        if data.edition != edition {
+            let data = BuiltCrateData {
+                edition,
+                ..data.clone()
+            };
+            krate.set_data(db).to(data);
+        }
+    }
 }
 
 impl Project {
@@ -156,63 +1508,139 @@ impl Project {
     }
 
     /// Like `scaffold`, but injects user code immediately.
-    pub fn scaffold_with_code<'a>(settings: ProjectSettings, source: &'a str) -> Result<Project> {
-        let parse_result = find_queries(source);
+    pub fn scaffold_with_code(settings: ProjectSettings, source: &str) -> Result<Project> {
+        let parse_result = find_queries(source, settings.tab_width);
         let source = parse_result.code;
         let queries = parse_result.queries;
+        let unplaced = parse_result.unplaced;
+        let source_lines = parse_result.source_lines;
+        let mut default_options = SnippetOptions::defaults(&settings);
 
         // Always use cargo mode - it's needed for std resolution and external deps
-        let bootstrap = bootstrap_project_in(
-            settings.tmpdir,
-            settings.project_name,
-            &source,
-            settings.cargo_toml,
-            settings.target_dir,
-        )?;
+        let start = Instant::now();
+        let package = settings
+            .workspace
+            .map(|workspace| workspace.package())
+            .transpose()?;
+        if let Some(package) = &package {
+            default_options.edition = package.edition;
+            default_options.crate_type = CrateType::Example;
+        }
+        let options = SnippetOptions::new(parse_result.directives, &default_options);
+        let bootstrap = match (settings.workspace, &package) {
+            (Some(workspace), Some(package)) => {
+                bootstrap_workspace(&settings, &workspace, package, &source, &options)?
+            }
+            _ => bootstrap_project_in(&settings, &source, &options)?,
+        };
+        if let Some(error) = bootstrap.error.as_ref().filter(|_| settings.strict) {
+            return Err(error.clone().into());
+        }
+        let bootstrap_ms = millis(start);
+
+        let start = Instant::now();
 
         let cargo_config = CargoConfig {
             sysroot: Some(RustLibSource::Discover),
+            target: bootstrap.target.clone(),
+            set_test: options.crate_type == CrateType::Test,
+            target_dir_config: match bootstrap.target_dir {
+                Some(target_dir) => {
+                    TargetDirectoryConfig::Directory(Utf8PathBuf::try_from(target_dir)?)
+                }
+                None => TargetDirectoryConfig::None,
+            },
+            features: options.features.cargo_features(),
+            cfg_overrides: options.features.cfg_overrides(),
+            extra_env: settings.offline.extra_env().into_iter().collect(),
+            run_build_script_command: bootstrap
+                .build_script_output
+                .map(|output| vec!["cat".to_string(), output.display().to_string()]),
             ..CargoConfig::default()
         };
         let no_progress = &|_| ();
+        let with_proc_macro_server = settings.proc_macro_server.choice()?;
         let load_cargo_config = LoadCargoConfig {
-            load_out_dirs_from_check: true,
-            with_proc_macro_server: ProcMacroServerChoice::None,
+            // Proc macros can only be expanded once they're built, which happens with the build
+            // scripts.
+            load_out_dirs_from_check: with_proc_macro_server != ProcMacroServerChoice::None,
+            with_proc_macro_server,
             prefill_caches: false,
         };
         let path = AbsPathBuf::assert_utf8(Utf8PathBuf::try_from(bootstrap.root).unwrap().into());
 
         let manifest = ProjectManifest::discover_single(&path)?;
-        let workspace = ProjectWorkspace::load(manifest, &cargo_config, no_progress)?;
-        let (db, vfs, _proc_macro) =
+        let mut workspace = ProjectWorkspace::load(manifest, &cargo_config, no_progress)?;
+        if let (Some(metadata), ProjectWorkspaceKind::Cargo { cargo, .. }) =
+            (bootstrap.metadata, &mut workspace.kind)
+        {
+            *cargo = CargoWorkspace::new(
+                metadata,
+                cargo.manifest_path().clone(),
+                cargo.env().clone(),
+                false,
+            );
+        }
+        if load_cargo_config.load_out_dirs_from_check {
+            let build_scripts = workspace.run_build_scripts(&cargo_config, no_progress)?;
+            workspace.set_build_scripts(build_scripts);
+        }
+        let (db, vfs, proc_macro) =
             load_workspace(workspace, &cargo_config.extra_env, &load_cargo_config)?;
-        let host = AnalysisHost::with_database(db);
-
-        let analysis = host.analysis();
-
-        let _si = StaticIndex::compute(&analysis, VendoredLibrariesConfig::Excluded);
+        let mut host = AnalysisHost::with_database(db);
 
         let (fid, _) = vfs
             .file_id(&VfsPath::new_real_path(
-                bootstrap.lib_rs.display().to_string(),
+                bootstrap.snippet_rs.display().to_string(),
             ))
-            .unwrap();
-        let analysis = host.analysis();
+            .with_context(|| {
+                format!(
+                    "{} is not part of the workspace",
+                    bootstrap.snippet_rs.display()
+                )
+            })?;
 
-        let (token_to_ranges, token_data, line_index, cut) = pre_index(&analysis, fid, &source);
+        // A Cargo.toml template has its own edition, which the snippet may override.
+        set_edition(&mut host, fid, options.edition);
+
+        let analysis = host.analysis();
+        let load_ms = millis(start);
+
+        let start = Instant::now();
+        let (tokens, line_index, cut) =
+            pre_index(Some(&host), &analysis, fid, &source, &settings.hover);
+        let timings = Timings {
+            bootstrap: Some(bootstrap_ms),
+            load: Some(load_ms),
+            index: millis(start),
+            analysis: 0.0,
+        };
 
         Ok(Project {
             cut,
+            source_lines,
 
             host: Some(host),
             analysis,
             queries,
+            unplaced,
 
             line_index,
-            token_to_ranges,
-            token_data,
+            tokens,
 
             fid,
+            options,
+            default_options,
+            warnings: bootstrap.warnings,
+            _proc_macro: proc_macro,
+            bootstrap_error: bootstrap.error,
+            workspace_error: None,
+            adjustments: settings.adjustments,
+            fixes: settings.fixes,
+            hover: settings.hover,
+            timings: settings.timings,
+            phase_timings: timings,
+            tab_width: settings.tab_width,
         })
     }
 
@@ -220,15 +1648,19 @@ impl Project {
         // The analysis is now stale. Drop it so that we don't block host update below.
         drop(self.analysis);
 
-        let parse_result = find_queries(&new_code);
+        let parse_result = find_queries(&new_code, self.tab_width);
         let new_code = parse_result.code;
         let queries = parse_result.queries;
+        let unplaced = parse_result.unplaced;
+        let source_lines = parse_result.source_lines;
+        let options = SnippetOptions::new(parse_result.directives, &self.default_options);
 
         let (host, analysis, fid) = match self.host {
             Some(mut host) => {
                 let mut changes = ChangeWithProcMacros::default();
                 changes.change_file(self.fid, Some(new_code.clone()));
                 host.apply_change(changes);
+                set_edition(&mut host, self.fid, options.edition);
                 let analysis = host.analysis();
                 (Some(host), analysis, self.fid)
             }
@@ -239,17 +1671,38 @@ impl Project {
             }
         };
 
-        let (token_to_ranges, token_data, line_index, cut) = pre_index(&analysis, fid, &new_code);
+        let start = Instant::now();
+        let (tokens, line_index, cut) =
+            pre_index(host.as_ref(), &analysis, fid, &new_code, &self.hover);
+        let timings = Timings {
+            bootstrap: None,
+            load: None,
+            index: millis(start),
+            analysis: 0.0,
+        };
 
         Self {
             host,
             analysis,
             queries,
+            unplaced,
             fid,
-            token_to_ranges,
-            token_data,
+            tokens,
             line_index,
             cut,
+            source_lines,
+            options,
+            default_options: self.default_options,
+            warnings: self.warnings,
+            _proc_macro: self._proc_macro,
+            bootstrap_error: self.bootstrap_error,
+            workspace_error: None,
+            adjustments: self.adjustments,
+            fixes: self.fixes,
+            hover: self.hover,
+            timings: self.timings,
+            phase_timings: timings,
+            tab_width: self.tab_width,
         }
     }
 
@@ -272,14 +1725,81 @@ impl Project {
         }
     }
 
+    /// Maps the edits to this file into post-cut coordinates, or returns `None` if any of them is
+    /// outside the cut, as the others alone would break the code.
+    fn source_edits(&self, edit: &TextEdit) -> Option<Vec<SourceEdit>> {
+        edit.iter()
+            .map(|indel| {
+                self.to_position(indel.delete).map(
+                    |Position {
+                         start,
+                         length,
+                         line,
+                         character,
+                     }| SourceEdit {
+                        start,
+                        length,
+                        line,
+                        character,
+                        new_text: indel.insert.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Reports directives that could not be applied, markers with nothing to point at and problems
+    /// setting up the workspace. They aren't part of the code, so they have no position.
+    fn setup_errors(&self) -> Vec<Error> {
+        let errors = self.options.errors.iter().map(|message| {
+            (
+                "invalid_directive",
+                DiagnosticCategory::Error,
+                message.clone(),
+            )
+        });
+        let marker_errors = self.unplaced.iter().map(|(_, line)| {
+            let message = format!("the marker on line {} has no line to point at", line + 1);
+            ("invalid_marker", DiagnosticCategory::Error, message)
+        });
+        let workspace_errors = self.workspace_error.iter().map(|message| {
+            (
+                "invalid_workspace",
+                DiagnosticCategory::Error,
+                message.clone(),
+            )
+        });
+        let warnings = self
+            .warnings
+            .iter()
+            .map(|message| ("workspace", DiagnosticCategory::Warning, message.clone()));
+        errors
+            .chain(marker_errors)
+            .chain(workspace_errors)
+            .chain(warnings)
+            .map(|(id, category, message)| Error {
+                rendered_message: message,
+                id: id.to_string(),
+                category,
+                code: 0,
+                start: 0,
+                length: 0,
+                line: 0,
+                character: 0,
+                fixes: None,
+                setup: true,
+            })
+            .collect()
+    }
+
     fn diagnostics(&self) -> Result<Vec<Error>> {
+        let resolve = match self.fixes {
+            true => AssistResolveStrategy::All,
+            false => AssistResolveStrategy::None,
+        };
         let diags = self
             .analysis
-            .full_diagnostics(
-                &DiagnosticsConfig::test_sample(),
-                AssistResolveStrategy::None,
-                self.fid,
-            )?
+            .full_diagnostics(&DiagnosticsConfig::test_sample(), resolve, self.fid)?
             .into_iter()
             .filter_map(|diag| {
                 let Diagnostic {
@@ -287,8 +1807,28 @@ impl Project {
                     message,
                     range,
                     severity,
+                    fixes,
                     ..
                 } = diag;
+                let fixes = match self.fixes {
+                    true => Some(
+                        fixes
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter_map(|fix| {
+                                let (edit, _) = fix
+                                    .source_change
+                                    .as_ref()?
+                                    .get_source_and_snippet_edit(self.fid)?;
+                                Some(Fix {
+                                    label: fix.label.to_string(),
+                                    edits: self.source_edits(edit)?,
+                                })
+                            })
+                            .collect(),
+                    ),
+                    false => None,
+                };
                 self.to_position(range.range).map(
                     |Position {
                          start,
@@ -305,6 +1845,8 @@ impl Project {
                             length,
                             line,
                             character,
+                            fixes,
+                            setup: false,
                         }
                     },
                 )
@@ -313,25 +1855,84 @@ impl Project {
         Ok(diags)
     }
 
+    fn adjustments(&self) -> Result<Vec<Adjustment>> {
+        let config = InlayHintsConfig {
+            render_colons: false,
+            type_hints: false,
+            sized_bound: false,
+            discriminant_hints: DiscriminantHints::Never,
+            parameter_hints: false,
+            parameter_hints_for_missing_arguments: false,
+            generic_parameter_hints: GenericParameterHints {
+                type_hints: false,
+                lifetime_hints: false,
+                const_hints: false,
+            },
+            chaining_hints: false,
+            adjustment_hints: AdjustmentHints::Always,
+            adjustment_hints_disable_reborrows: false,
+            adjustment_hints_mode: AdjustmentHintsMode::Prefix,
+            adjustment_hints_hide_outside_unsafe: false,
+            closure_return_type_hints: ClosureReturnTypeHints::Never,
+            closure_capture_hints: false,
+            binding_mode_hints: true,
+            implicit_drop_hints: true,
+            implied_dyn_trait_hints: false,
+            lifetime_elision_hints: LifetimeElisionHints::Never,
+            param_names_for_lifetime_elision_hints: false,
+            hide_inferred_type_hints: false,
+            hide_named_constructor_hints: false,
+            hide_closure_initialization_hints: false,
+            hide_closure_parameter_hints: false,
+            range_exclusive_hints: false,
+            closure_style: ClosureStyle::ImplFn,
+            max_length: None,
+            closing_brace_hints_min_lines: None,
+            fields_to_resolve: InlayFieldsToResolve::empty(),
+            minicore: MiniCore::default(),
+        };
+        let adjustments = self
+            .analysis
+            .inlay_hints(&config, self.fid, None)?
+            .into_iter()
+            .filter_map(|hint| {
+                let kind = match hint.kind {
+                    InlayKind::Adjustment => AdjustmentKind::Adjustment,
+                    InlayKind::BindingMode => AdjustmentKind::BindingMode,
+                    InlayKind::Drop => AdjustmentKind::Drop,
+                    _ => return None,
+                };
+                let position = match hint.position {
+                    InlayHintPosition::Before => AdjustmentPosition::Before,
+                    InlayHintPosition::After => AdjustmentPosition::After,
+                };
+                self.to_position(hint.range).map(
+                    |Position {
+                         start,
+                         length,
+                         line,
+                         character,
+                     }| Adjustment {
+                        kind,
+                        label: hint.label.to_string(),
+                        position,
+                        start,
+                        length,
+                        line,
+                        character,
+                    },
+                )
+            })
+            .collect();
+        Ok(adjustments)
+    }
+
     fn ident_hovers(&self) -> Result<Vec<StaticQuickInfo>> {
         let hovers = self
-            .token_data
+            .tokens
             .iter()
-            .filter_map(|(id, token)| token.hover.as_ref().map(|hover| (id, hover)))
-            .flat_map(|(id, hover): (&TokenId, &HoverResult)| {
-                self.token_to_ranges
-                    .get(&id)
-                    .map(|ranges| {
-                        // Annoying, but we have to do this here. We can't unwrap_or_default first
-                        // because then we take a reference to a Vec, and rustc thinks we return
-                        // meaningful data inside that temporary.
-                        ranges
-                            .iter()
-                            .map(|range| (range, hover))
-                            .collect::<Vec<_>>()
-                    })
-                    .unwrap_or_default()
-            })
+            .filter_map(|token| token.hover.as_ref().map(|hover| (token, hover)))
+            .flat_map(|(token, hover)| token.ranges.iter().map(move |range| (range, hover)))
             .filter_map(|(range, hover)| {
                 self.to_position(*range).map(
                     |Position {
@@ -364,19 +1965,19 @@ impl Project {
     }
 
     fn find_hover_data_at_position(&self, pos: TextSize) -> Option<(TextRange, &HoverResult)> {
-        // Find all tokens containing this position, then pick the smallest (most specific) one
+        // Find all tokens containing this position, then pick the smallest (most specific) one.
+        // The module's hover spans the whole file, so it would answer any position nothing else
+        // does.
+        let file = TextRange::up_to(self.line_index.len());
         let mut candidates: Vec<(TextRange, &HoverResult)> = self
-            .token_data
+            .tokens
             .iter()
-            .filter_map(|(id, data)| {
-                let range = self
-                    .token_to_ranges
-                    .get(id)
-                    .and_then(|ranges| ranges.iter().find(|range| range.contains(pos)));
-                match (range, data.hover.as_ref()) {
-                    (Some(range), Some(hover)) => Some((*range, hover)),
-                    _ => None,
-                }
+            .filter_map(|token| {
+                let range = token
+                    .ranges
+                    .iter()
+                    .find(|range| range.contains(pos) && **range != file)?;
+                Some((*range, token.hover.as_ref()?))
             })
             .collect();
 
@@ -413,6 +2014,9 @@ impl Project {
             length,
             completions: None,
             completions_prefix: None,
+            error: None,
+            edits: None,
+            transformed: None,
         })
     }
 
@@ -491,26 +2095,290 @@ impl Project {
             length,
             completions: Some(completions),
             completions_prefix: Some(target_string),
+            error: None,
+            edits: None,
+            transformed: None,
         })
     }
 
-    fn queries(&self) -> Vec<Query> {
-        self.queries
-            .iter()
-            .filter_map(|(kind, pos)| {
-                match kind {
-                    QueryKind::Query => self.query(*pos),
-                    QueryKind::Completions => self.completions(*pos),
+    fn const_eval(&self, pos: TextSize) -> Result<Query> {
+        // Const evaluation goes through hir, which needs the database of a cargo project.
+        let db = match &self.host {
+            None => return Err(anyhow::Error::msg("")),
+            Some(host) => host.raw_database(),
+        };
+        attach_db(db, || self.const_eval_in(db, pos))
+    }
+
+    fn const_eval_in(&self, db: &RootDatabase, pos: TextSize) -> Result<Query> {
+        let zero_err = || anyhow::Error::msg("");
+        let sema = Semantics::new(db);
+        let file = sema.parse_guess_edition(self.fid);
+        let token = file
+            .syntax()
+            .token_at_offset(pos)
+            .max_by_key(|token| token.kind() == SyntaxKind::IDENT)
+            .ok_or_else(zero_err)?;
+
+        // Prefer the const or static the caret points at, then the array length or const generic
+        // argument the caret is in, falling back to the const or static whose body the caret is in.
+        let referenced = IdentClass::classify_token(&sema, &token)
+            .into_iter()
+            .flat_map(IdentClass::definitions_no_ops)
+            .find_map(|def| match def {
+                Definition::Const(it) => Some(DefWithBody::from(it)),
+                Definition::Static(it) => Some(DefWithBody::from(it)),
+                _ => None,
+            })
+            .map(|def| (token.text_range(), def));
+        let (range, def) = match referenced {
+            Some(referenced) => referenced,
+            None => match const_arg_value(&sema, &token) {
+                Some((range, Ok(text))) => return self.const_eval_query(range, Some(text), None),
+                Some((range, Err(error))) => {
+                    return self.const_eval_query(range, None, Some(error))
                 }
-                .ok()
+                None => token
+                    .parent_ancestors()
+                    .find_map(|node| match_ast! {
+                        match node {
+                            ast::Const(it) => Some((it.name()?.syntax().text_range(), sema.to_def(&it)?.into())),
+                            ast::Static(it) => Some((it.name()?.syntax().text_range(), sema.to_def(&it)?.into())),
+                            _ => None,
+                        }
+                    })
+                    .ok_or_else(zero_err)?,
+            },
+        };
+
+        let display_target = def.module(db).krate(db).to_display_target(db);
+        let evaluated = match def {
+            DefWithBody::Const(it) => it.eval(db),
+            DefWithBody::Static(it) => it.eval(db),
+            DefWithBody::Function(_) | DefWithBody::Variant(_) => return Err(zero_err()),
+        };
+        let (text, error) = match evaluated {
+            Ok(value) => {
+                let text = value
+                    .render_debug(db)
+                    .unwrap_or_else(|_| value.render(db, display_target));
+                (Some(text), None)
+            }
+            Err(err) => {
+                // Point at spans in the snippet by their line and column in the cut.
+                let span_formatter = |file_id, range: TextRange| match file_id == self.fid {
+                    true => {
+                        let LineCol { line, col } = self.line_index.line_col(range.start());
+                        format!("{}:{}", line.saturating_sub(self.cut.start_line), col)
+                    }
+                    false => "<external>".to_string(),
+                };
+                let mut message = String::new();
+                err.pretty_print(&mut message, db, span_formatter, display_target)?;
+                (None, Some(message))
+            }
+        };
+        self.const_eval_query(range, text, error)
+    }
+
+    /// The answer to a `^=` query about the const expression at `range`.
+    fn const_eval_query(
+        &self,
+        range: TextRange,
+        text: Option<String>,
+        error: Option<String>,
+    ) -> Result<Query> {
+        let Position {
+            start,
+            length,
+            line,
+            character,
+        } = self
+            .to_position(range)
+            .ok_or_else(|| anyhow::Error::msg(""))?;
+        Ok(Query {
+            kind: QueryKind::ConstEval,
+            line: line + 1,
+            offset: character,
+            text,
+            docs: None,
+            start,
+            length,
+            completions: None,
+            completions_prefix: None,
+            error,
+            edits: None,
+            transformed: None,
+        })
+    }
+
+    fn assist(&self, assist_id: &str, pos: TextSize) -> Result<Query> {
+        let assist_config = AssistConfig {
+            snippet_cap: None,
+            allowed: None,
+            insert_use: InsertUseConfig {
+                granularity: ImportGranularity::Crate,
+                prefix_kind: PrefixKind::Plain,
+                enforce_granularity: true,
+                group: true,
+                skip_glob_imports: true,
+            },
+            prefer_no_std: false,
+            prefer_prelude: true,
+            prefer_absolute: false,
+            assist_emit_must_use: false,
+            term_search_fuel: 400,
+            term_search_borrowck: true,
+            code_action_grouping: false,
+            expr_fill_default: ExprFillDefaultMode::Todo,
+            prefer_self_ty: false,
+            show_rename_conflicts: true,
+        };
+        let frange = FileRange {
+            file_id: self.fid,
+            range: TextRange::empty(pos),
+        };
+        let find_assist = |resolve| -> Result<_> {
+            Ok(self
+                .analysis
+                .assists_with_fixes(
+                    &assist_config,
+                    &DiagnosticsConfig::test_sample(),
+                    resolve,
+                    frange,
+                )?
+                .into_iter()
+                .find(|assist| assist.id.0 == assist_id))
+        };
+        // Look the assist up first, so that only its edits are computed, like an editor does when
+        // one is picked from the list.
+        let assist = match find_assist(AssistResolveStrategy::None)? {
+            Some(assist) => find_assist(AssistResolveStrategy::Single(SingleResolve {
+                assist_id: assist_id.to_string(),
+                assist_kind: assist.id.1,
+                assist_subtype: assist.id.2,
+            }))?,
+            None => None,
+        };
+
+        let Position {
+            start,
+            line,
+            character,
+            ..
+        } = self
+            .to_position(frange.range)
+            .ok_or_else(|| anyhow::Error::msg(""))?;
+        let mut query = Query {
+            kind: QueryKind::Assist,
+            line: line + 1,
+            offset: character,
+            text: None,
+            docs: None,
+            start,
+            length: 0,
+            completions: None,
+            completions_prefix: None,
+            error: None,
+            edits: None,
+            transformed: None,
+        };
+
+        let edit = assist.as_ref().and_then(|assist| {
+            let source_change = assist.source_change.as_ref()?;
+            let (edit, _) = source_change.get_source_and_snippet_edit(self.fid)?;
+            Some(edit)
+        });
+        let (assist, edit) = match (assist.as_ref(), edit) {
+            (Some(assist), Some(edit)) => (assist, edit),
+            _ => {
+                query.error = Some(format!("assist `{}` is not applicable here", assist_id));
+                return Ok(query);
+            }
+        };
+
+        let edits = self.source_edits(edit);
+
+        let mut new_source = self.analysis.file_text(self.fid)?.to_string();
+        edit.apply(&mut new_source);
+        let new_cut = Cut::new(&new_source, &LineIndex::new(&new_source));
+
+        query.text = Some(assist.label.to_string());
+        query.edits = edits;
+        query.transformed = Some(new_cut.source);
+        Ok(query)
+    }
+
+    /// The line of the snippet, markers and directives included, that a line of the code is on.
+    /// Lines of the result are counted from the cut instead; see `result_line`.
+    fn snippet_line(&self, line: u32) -> u32 {
+        self.source_lines
+            .get(line as usize)
+            .copied()
+            .unwrap_or(line)
+    }
+
+    /// The line of the snippet, markers and directives included, that a line of the result is on.
+    pub fn result_line(&self, line: u32) -> u32 {
+        self.snippet_line(line + self.cut.start_line)
+    }
+
+    /// The line of the result a tag is on, which is the line its directive was above.
+    pub fn tag_line(&self, tag: &Tag) -> u32 {
+        let line = self.source_lines.partition_point(|&line| line < tag.line) as u32;
+        line.saturating_sub(self.cut.start_line)
+    }
+
+    /// What each marker of the snippet came to, in source order, which is the order `twoslasher`
+    /// reports queries in.
+    pub fn markers(&self) -> Vec<Marker> {
+        let placed = self.queries.iter().map(|(kind, pos)| {
+            let answer = match kind {
+                MarkerKind::Query => self.query(*pos),
+                MarkerKind::Completions => self.completions(*pos),
+                MarkerKind::ConstEval => self.const_eval(*pos),
+                MarkerKind::Assist(assist_id) => self.assist(assist_id, *pos),
+            };
+            let line = self.line_index.line_col(*pos).line;
+            Marker {
+                kind: kind.query_kind(),
+                line: self.snippet_line(line),
+                in_cut: self.cut.line_in_cut(line),
+                placed: true,
+                answer: answer.ok(),
+            }
+        });
+        // Markers without a line above come before all the code, and so before the others.
+        self.unplaced
+            .iter()
+            .map(|(kind, line)| Marker {
+                kind: kind.query_kind(),
+                line: *line,
+                in_cut: false,
+                placed: false,
+                answer: None,
             })
+            .chain(placed)
+            .collect()
+    }
+
+    fn queries(&self) -> Vec<Query> {
+        self.markers()
+            .into_iter()
+            .filter_map(|marker| marker.answer)
             .collect()
     }
 
     pub fn twoslasher(&self) -> Result<TwoSlash> {
-        let errors = self.diagnostics()?;
+        let start = Instant::now();
+        let mut errors = self.setup_errors();
+        errors.extend(self.diagnostics()?);
         let static_quick_infos = self.ident_hovers()?;
         let queries = self.queries();
+        let adjustments = match self.adjustments {
+            true => Some(self.adjustments()?),
+            false => None,
+        };
 
         let two_slash_result = TwoSlash {
             code: self.cut.source.to_string(),
@@ -518,9 +2386,14 @@ impl Project {
             highlights: vec![],
             static_quick_infos,
             queries,
-            // TODO: real tags
-            tags: vec![],
+            tags: self.options.tags.clone(),
             errors,
+            adjustments,
+            bootstrap_error: self.bootstrap_error.clone(),
+            timings: self.timings.then(|| Timings {
+                analysis: millis(start),
+                ..self.phase_timings.clone()
+            }),
             // TODO: real URL
             playground_url: "https://play.rust-lang.org".to_string(),
         };
@@ -528,6 +2401,75 @@ impl Project {
     }
 }
 
+/// The array length or const generic argument that `token` is in, and its value or why it has
+/// none. rust-analyzer evaluates these as part of the type they belong to, so that is where the
+/// value comes from. In types it only evaluates lengths and arguments that are literals or paths,
+/// and inline `const` blocks and the const arguments of functions aren't evaluated at all.
+fn const_arg_value(
+    sema: &Semantics<RootDatabase>,
+    token: &SyntaxToken,
+) -> Option<(TextRange, Result<String, String>)> {
+    let db = sema.db;
+    let display_target = sema.scope(&token.parent()?)?.krate().to_display_target(db);
+    let contains_token = |node: &SyntaxNode| node.text_range().contains_range(token.text_range());
+    let unevaluated = || {
+        "rust-analyzer only evaluates array lengths and const arguments in types that are \
+         literals or paths"
+            .to_string()
+    };
+    for node in token.parent_ancestors() {
+        if ast::Item::can_cast(node.kind()) {
+            break;
+        }
+        if let Some(array) = ast::ArrayType::cast(node.clone()) {
+            let length = array
+                .const_arg()
+                .filter(|length| contains_token(length.syntax()))?;
+            let value = match sema.resolve_type(&array.into())?.as_array(db) {
+                Some((_, value)) => Ok(value.to_string()),
+                None => Err(unevaluated()),
+            };
+            return Some((length.syntax().text_range(), value));
+        }
+        if let Some(array) = ast::ArrayExpr::cast(node.clone()) {
+            array.semicolon_token()?;
+            let length = array
+                .exprs()
+                .nth(1)
+                .filter(|length| contains_token(length.syntax()))?;
+            let array_type = sema.type_of_expr(&array.into())?.original;
+            let (_, value) = array_type.as_array(db)?;
+            return Some((length.syntax().text_range(), Ok(value.to_string())));
+        }
+        // Array lengths in types are const arguments too, but not generic ones.
+        let generic_arg = ast::ConstArg::cast(node.clone()).and_then(|arg| {
+            Some((
+                arg.clone(),
+                ast::GenericArgList::cast(arg.syntax().parent()?)?,
+            ))
+        });
+        if let Some((arg, args)) = generic_arg {
+            // The argument's place among the type and const arguments, which are what the type
+            // reports values for.
+            let index = args
+                .generic_args()
+                .filter(|arg| !matches!(arg, ast::GenericArg::LifetimeArg(_)))
+                .position(|other| other.syntax() == arg.syntax())?;
+            let path_type = args.syntax().ancestors().find_map(ast::PathType::cast)?;
+            let value = sema
+                .resolve_type(&path_type.into())?
+                .type_and_const_arguments(db, display_target)
+                .nth(index)?;
+            let value = match value.as_str() {
+                "_" => Err(unevaluated()),
+                _ => Ok(value.to_string()),
+            };
+            return Some((arg.syntax().text_range(), value));
+        }
+    }
+    None
+}
+
 struct Cut {
     source: String,
     start_line: u32,
@@ -537,28 +2479,26 @@ struct Cut {
 
 impl Cut {
     fn new(basis: &str, line_index: &LineIndex) -> Cut {
-        static CUT_BEFORE_STR: &'static str = "// ---cut---\n";
-        static CUT_AFTER_STR: &'static str = "// ---cut-after---\n";
+        static CUT_BEFORE_STR: &str = "// ---cut---";
+        static CUT_AFTER_STR: &str = "// ---cut-after---";
 
-        let (start_line, start_offset) = basis
-            .find(CUT_BEFORE_STR)
-            .map(|offset| {
+        let (start_line, start_offset) = find_cut_line(basis, CUT_BEFORE_STR)
+            .map(|(offset, len)| {
                 let LineCol { line, .. } = line_index.line_col(TextSize::from(offset as u32));
                 let start_line = line + 1;
-                let start_offset = (offset + CUT_BEFORE_STR.len()) as u32;
+                let start_offset = (offset + len) as u32;
                 (start_line, start_offset)
             })
             .unwrap_or((0, 0));
-        let (end_line, end_offset) = basis
-            .find(CUT_AFTER_STR)
-            .map(|offset| {
+        let (end_line, end_offset) = find_cut_line(basis, CUT_AFTER_STR)
+            .map(|(offset, _)| {
                 let end_line = line_index.line_col(TextSize::from(offset as u32)).line;
                 let end_offset = offset as u32; // We'll pick out the trailing newline elsewhere
                 (end_line, end_offset)
             })
             .unwrap_or_else(|| {
                 let end_offset = basis.len() as u32;
-                let end_line = line_index.line_col(TextSize::from(end_offset as u32)).line + 1;
+                let end_line = line_index.line_col(TextSize::from(end_offset)).line + 1;
                 (end_line, end_offset)
             });
         let substr = basis[start_offset as usize..end_offset as usize].to_string();
@@ -575,6 +2515,23 @@ impl Cut {
     }
 }
 
+/// Finds `marker` followed by a line ending, either `\n` or `\r\n`, and returns where it starts and
+/// how long it is with the line ending.
+fn find_cut_line(basis: &str, marker: &str) -> Option<(usize, usize)> {
+    basis.match_indices(marker).find_map(|(offset, _)| {
+        let rest = &basis[offset + marker.len()..];
+        let ending = ["\n", "\r\n"]
+            .into_iter()
+            .find(|ending| rest.starts_with(ending))?;
+        Some((offset, marker.len() + ending.len()))
+    })
+}
+
+/// The milliseconds since `start`
+fn millis(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
 fn ra_hover_to_text(markup: String) -> String {
     markup
         .trim()
@@ -1,20 +1,85 @@
 #[cfg(test)]
 mod tests {
-    use crate::project::{Project, ProjectSettings};
-    use crate::twoslash::TwoSlash;
+    use crate::check;
+    use crate::html;
+    use crate::markdown::twoslash_markdown;
+    use crate::mdbook;
+    use crate::nodes;
+    use crate::project::{
+        CrateType, FeatureSettings, HoverSettings, LocalSource, OfflineSettings, ProcMacroServer,
+        Project, ProjectPool, ProjectSettings, WorkspaceSettings,
+    };
+    use crate::rustdoc;
+    use crate::terminal;
+    use crate::twoslash::{BootstrapError, DiagnosticCategory, Error, PositionEncoding, TwoSlash};
     use insta::assert_snapshot;
+    use ra_ide::Edition;
     use tempfile::TempDir;
 
     fn twoslash(source: &str) -> TwoSlash {
+        twoslash_with(source, |_| {})
+    }
+
+    fn twoslash_with(source: &str, configure: impl FnOnce(&mut ProjectSettings)) -> TwoSlash {
         let tmpdir = TempDir::new().unwrap();
-        let settings = ProjectSettings {
+        let mut settings = settings(&tmpdir);
+        configure(&mut settings);
+        let project = Project::scaffold_with_code(settings, source.trim()).unwrap();
+        project.twoslasher().unwrap()
+    }
+
+    fn settings(tmpdir: &TempDir) -> ProjectSettings<'_> {
+        ProjectSettings {
             project_name: "test-project",
-            tmpdir: &tmpdir,
+            tmpdir,
             cargo_toml: None,
             target_dir: None,
-        };
-        let project = Project::scaffold_with_code(settings, source.trim()).unwrap();
-        project.twoslasher().unwrap()
+            cache_dir: None,
+            edition: Edition::Edition2021,
+            target: None,
+            crate_type: CrateType::Lib,
+            adjustments: false,
+            fixes: false,
+            hover: HoverSettings::default(),
+            features: FeatureSettings::default(),
+            proc_macro_server: ProcMacroServer::None,
+            strict: false,
+            offline: OfflineSettings::default(),
+            timings: false,
+            workspace: None,
+            tab_width: 4,
+        }
+    }
+
+    /// A local crate that snippets can depend on by path
+    fn greeting_crate() -> TempDir {
+        let dependency = TempDir::new().unwrap();
+        std::fs::write(
+            dependency.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "greeting"
+version = "0.1.0"
+edition = "2021"
+
+[features]
+loud = []
+"#,
+        )
+        .unwrap();
+        std::fs::create_dir(dependency.path().join("src")).unwrap();
+        std::fs::write(
+            dependency.path().join("src/lib.rs"),
+            r#"
+/// Says hello
+pub fn hello() -> &'static str { "hello" }
+
+#[cfg(feature = "loud")]
+pub fn shout() -> &'static str { "HELLO" }
+"#,
+        )
+        .unwrap();
+        dependency
     }
 
     fn snapshot(result: &TwoSlash) -> String {
@@ -1397,4 +1462,2043 @@ pub fn example() {
         }
         "#);
     }
+
+    #[test]
+    fn test_adjustments() {
+        let result = twoslash_with(
+            r#"
+pub fn example(names: &Vec<String>) -> usize {
+    let first = &names[0];
+    let (a, b) = &(1, 2);
+    first.len() + a + b
+}
+"#,
+            |settings| settings.adjustments = true,
+        );
+
+        let adjustments = result.adjustments.as_ref().unwrap();
+        assert!(!adjustments.is_empty());
+
+        assert_snapshot!(serde_json::to_string_pretty(adjustments).unwrap(), @r#"
+        [
+          {
+            "kind": "adjustment",
+            "label": "(&*",
+            "position": "before",
+            "start": 64,
+            "length": 5,
+            "line": 1,
+            "character": 17
+          },
+          {
+            "kind": "adjustment",
+            "label": ")",
+            "position": "after",
+            "start": 64,
+            "length": 5,
+            "line": 1,
+            "character": 17
+          },
+          {
+            "kind": "bindingMode",
+            "label": "&",
+            "position": "before",
+            "start": 82,
+            "length": 6,
+            "line": 2,
+            "character": 8
+          },
+          {
+            "kind": "bindingMode",
+            "label": "ref",
+            "position": "before",
+            "start": 83,
+            "length": 1,
+            "line": 2,
+            "character": 9
+          },
+          {
+            "kind": "bindingMode",
+            "label": "ref",
+            "position": "before",
+            "start": 86,
+            "length": 1,
+            "line": 2,
+            "character": 12
+          },
+          {
+            "kind": "adjustment",
+            "label": "(&*",
+            "position": "before",
+            "start": 104,
+            "length": 5,
+            "line": 3,
+            "character": 4
+          },
+          {
+            "kind": "adjustment",
+            "label": ")",
+            "position": "after",
+            "start": 104,
+            "length": 5,
+            "line": 3,
+            "character": 4
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_adjustments_disabled_by_default() {
+        let result = twoslash(
+            r#"
+pub fn example(names: &Vec<String>) -> usize {
+    names.len()
+}
+"#,
+        );
+
+        assert!(result.adjustments.is_none());
+    }
+
+    #[test]
+    fn test_hover_memory_layout() {
+        let result = twoslash_with(
+            r#"
+pub struct Pair {
+    pub a: u64,
+    pub b: u8,
+}
+
+pub fn example() {
+    let p = Pair { a: 1, b: 2 };
+    //  ^?
+}
+"#,
+            |settings| {
+                settings.hover.memory_layout = true;
+                settings.hover.documentation = false;
+            },
+        );
+
+        let pair = result
+            .static_quick_infos
+            .iter()
+            .find(|info| info.target_string == "Pair")
+            .unwrap();
+        assert_snapshot!(pair.text, @"
+        test_project
+
+        pub struct Pair {
+            pub a: u64,
+            pub b: u8,
+        }
+
+        ---
+
+        size = 16 (0x10), align = 8, largest padding = 7
+        ");
+
+        let field = result
+            .static_quick_infos
+            .iter()
+            .find(|info| info.target_string == "b")
+            .unwrap();
+        assert_snapshot!(field.text, @"
+        test_project::Pair
+
+        pub b: u8
+
+        ---
+
+        size = 1, align = 1, offset = 8
+        ");
+
+        assert_eq!(result.queries.len(), 1);
+        assert_snapshot!(result.queries[0].text.as_ref().unwrap(), @"
+        let p: Pair
+
+        ---
+
+        size = 16 (0x10), align = 8
+        ");
+    }
+
+    #[test]
+    fn test_hover_trait() {
+        let result = twoslash(
+            r#"
+pub trait Shape {
+    fn area(&self) -> f64;
+}
+"#,
+        );
+
+        let hover = |target: &str| {
+            result
+                .static_quick_infos
+                .iter()
+                .find(|info| info.target_string == target)
+                .unwrap()
+                .text
+                .clone()
+        };
+        assert_snapshot!(hover("Shape"), @"
+        test_project
+
+        pub trait Shape
+        ");
+        assert_snapshot!(hover("area"), @"
+        test_project::Shape
+
+        pub trait Shape
+        pub fn area(&self) -> f64
+        ");
+    }
+
+    #[test]
+    fn test_hover_drop_glue() {
+        let result = twoslash_with(
+            r#"
+pub struct Name(pub String);
+
+pub struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {}
+}
+"#,
+            |settings| settings.hover.drop_glue = true,
+        );
+
+        let hover = |target: &str| {
+            result
+                .static_quick_infos
+                .iter()
+                .find(|info| info.target_string == target)
+                .unwrap()
+                .text
+                .clone()
+        };
+        assert_snapshot!(hover("Name"), @"
+        test_project
+
+        pub struct Name(pub String)
+
+        ---
+
+        needs Drop
+        ");
+        assert_snapshot!(hover("Guard"), @"
+        test_project
+
+        pub struct Guard
+
+        ---
+
+        impl Drop
+        ");
+    }
+
+    #[test]
+    fn test_const_eval_query() {
+        let result = twoslash(
+            r#"
+pub const fn square(n: usize) -> usize {
+    n * n
+}
+
+pub const AREA: usize = square(12);
+//        ^=
+pub static GREETING: &str = "hello";
+//         ^=
+pub const OVERFLOW: u8 = 255 + 1;
+//        ^=
+
+pub fn example() -> [u8; AREA] {
+    //                   ^=
+    [0; AREA]
+}
+"#,
+        );
+
+        assert_snapshot!(serde_json::to_string_pretty(&result.queries).unwrap(), @r#"
+        [
+          {
+            "kind": "constEval",
+            "line": 5,
+            "offset": 10,
+            "text": "144 (0x90)",
+            "start": 64,
+            "length": 4
+          },
+          {
+            "kind": "constEval",
+            "line": 6,
+            "offset": 11,
+            "text": "\"hello\"",
+            "start": 101,
+            "length": 8
+          },
+          {
+            "kind": "constEval",
+            "line": 7,
+            "offset": 10,
+            "start": 137,
+            "length": 8,
+            "error": "Mir eval error:\nPanic with message:\n\"Overflow in Add\"\n"
+          },
+          {
+            "kind": "constEval",
+            "line": 9,
+            "offset": 25,
+            "text": "144 (0x90)",
+            "start": 187,
+            "length": 4
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_const_eval_array_length_and_const_argument() {
+        let result = twoslash(
+            r#"
+pub const SIDE: usize = 3;
+pub struct Grid<const W: usize, const H: usize>;
+
+pub fn cells() -> [u8; 9] {
+    //                 ^=
+    [0; SIDE + 6]
+    //       ^=
+}
+
+pub type Square = Grid<SIDE, 3>;
+//                           ^=
+pub type Wide = Grid<{ SIDE * 4 }, 2>;
+//                          ^=
+"#,
+        );
+
+        let answers: Vec<_> = result
+            .queries
+            .iter()
+            .map(|query| (query.text.as_deref(), query.error.as_deref()))
+            .collect();
+        let unevaluated = "rust-analyzer only evaluates array lengths and const arguments in \
+                           types that are literals or paths";
+        assert_eq!(
+            answers,
+            vec![
+                (Some("9"), None),
+                (Some("9"), None),
+                (Some("3"), None),
+                (None, Some(unevaluated)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assist_query() {
+        let result = twoslash(
+            r#"
+pub fn example() -> i32 {
+    let total = 1 + 2;
+    //  ^ @assist: inline_local_variable
+    total * 2
+}
+
+pub fn unchanged() {}
+// ^ @assist: inline_local_variable
+"#,
+        );
+
+        assert_snapshot!(serde_json::to_string_pretty(&result.queries).unwrap(), @r#"
+        [
+          {
+            "kind": "assist",
+            "line": 2,
+            "offset": 8,
+            "text": "Inline variable",
+            "start": 34,
+            "length": 0,
+            "edits": [
+              {
+                "start": 30,
+                "length": 3,
+                "line": 1,
+                "character": 4,
+                "newText": "(1 + 2)"
+              },
+              {
+                "start": 34,
+                "length": 5,
+                "line": 1,
+                "character": 8,
+                "newText": "*"
+              },
+              {
+                "start": 40,
+                "length": 24,
+                "line": 1,
+                "character": 14,
+                "newText": "2\n}"
+              }
+            ],
+            "transformed": "pub fn example() -> i32 {\n    (1 + 2) * 2\n}\n\npub fn unchanged() {}"
+          },
+          {
+            "kind": "assist",
+            "line": 6,
+            "offset": 3,
+            "start": 69,
+            "length": 0,
+            "error": "assist `inline_local_variable` is not applicable here"
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_error_fixes() {
+        let result = twoslash_with(
+            r#"
+pub fn example() -> i32 {
+    let count = 1;
+    count += 1;
+    count
+}
+"#,
+            |settings| settings.fixes = true,
+        );
+
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @r#"
+        [
+          {
+            "renderedMessage": "cannot mutate immutable variable `count`",
+            "id": "E0384",
+            "category": "Error",
+            "code": 0,
+            "start": 49,
+            "length": 10,
+            "line": 2,
+            "character": 4,
+            "fixes": [
+              {
+                "label": "Change it to be mutable",
+                "edits": [
+                  {
+                    "start": 34,
+                    "length": 0,
+                    "line": 1,
+                    "character": 8,
+                    "newText": "mut "
+                  }
+                ]
+              }
+            ]
+          }
+        ]
+        "#);
+
+        // A fix that would edit the code before the cut isn't offered, as it can't be applied
+        let result = twoslash_with(
+            r#"
+pub fn example() -> i32 {
+    let count = 1;
+// ---cut---
+    count += 1;
+    count
+}
+"#,
+            |settings| settings.fixes = true,
+        );
+        assert_eq!(result.errors[0].id, "E0384");
+        assert!(result.errors[0].fixes.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_edition_directive() {
+        let source = r#"
+// @edition: 2015
+// @title: Raw identifiers
+pub fn async() {}
+"#;
+        let result = twoslash(source);
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @"[]");
+        assert_snapshot!(serde_json::to_string_pretty(&result.tags).unwrap(), @r#"
+        [
+          {
+            "name": "title",
+            "line": 1,
+            "annotation": "Raw identifiers"
+          }
+        ]
+        "#);
+
+        let result = twoslash(&source.replace("2015", "2018"));
+        assert_eq!(
+            result.errors[0].rendered_message,
+            "Syntax Error: expected a name"
+        );
+    }
+
+    #[test]
+    fn test_invalid_edition_directive() {
+        let result = twoslash(
+            r#"
+// @edition: 2025
+pub fn example() {}
+"#,
+        );
+
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @r#"
+        [
+          {
+            "renderedMessage": "invalid edition: \"2025\"",
+            "id": "invalid_directive",
+            "category": "Error",
+            "code": 0,
+            "start": 0,
+            "length": 0,
+            "line": 0,
+            "character": 0
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_marker_without_line_above() {
+        let result = twoslash(
+            r#"
+//  ^?
+pub fn example() {}
+"#,
+        );
+
+        assert!(result.queries.is_empty());
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @r#"
+        [
+          {
+            "renderedMessage": "the marker on line 1 has no line to point at",
+            "id": "invalid_marker",
+            "category": "Error",
+            "code": 0,
+            "start": 0,
+            "length": 0,
+            "line": 0,
+            "character": 0
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_dependency_directive() {
+        let dependency = greeting_crate();
+
+        let result = twoslash(&format!(
+            r#"
+// @dependency: greeting = {{ path = {:?} }}
+pub fn example() -> &'static str {{
+    greeting::hello()
+    //        ^?
+}}
+"#,
+            dependency.path().display().to_string()
+        ));
+
+        assert_snapshot!(serde_json::to_string_pretty(&result.queries).unwrap(), @r#"
+        [
+          {
+            "kind": "query",
+            "line": 2,
+            "offset": 14,
+            "text": "greeting\n\npub fn hello() -> &'static str\n\n---\n\nSays hello",
+            "start": 49,
+            "length": 5
+          }
+        ]
+        "#);
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @"[]");
+    }
+
+    #[test]
+    fn test_invalid_dependency_directive() {
+        let result = twoslash(
+            r#"
+// @dependency: serde
+pub fn example() {}
+"#,
+        );
+
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @r#"
+        [
+          {
+            "renderedMessage": "invalid dependency: \"serde\"",
+            "id": "invalid_directive",
+            "category": "Error",
+            "code": 0,
+            "start": 0,
+            "length": 0,
+            "line": 0,
+            "character": 0
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_dependency_directive_replaces_template_dependency() {
+        let dependency = greeting_crate();
+        let tmpdir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            cargo_toml: Some(
+                "[package]\nname = \"test-project\"\nversion = \"0.0.0\"\n\n\
+                 [dependencies]\ngreeting = { path = \"/nonexistent/greeting\" }\n",
+            ),
+            ..settings(&tmpdir)
+        };
+        let source = format!(
+            r#"
+// @dependency: greeting = {{ path = {:?} }}
+pub fn example() -> &'static str {{
+    greeting::hello()
+    //        ^?
+}}
+"#,
+            dependency.path().display().to_string()
+        );
+
+        let project = Project::scaffold_with_code(settings, source.trim()).unwrap();
+        let result = project.twoslasher().unwrap();
+        assert!(result.bootstrap_error.is_none());
+        assert!(result.errors.is_empty());
+        assert_eq!(
+            result.queries[0].text.as_deref(),
+            Some("greeting\n\npub fn hello() -> &'static str\n\n---\n\nSays hello")
+        );
+    }
+
+    #[test]
+    fn test_dependency_directive_with_commented_template_header() {
+        let dependency = greeting_crate();
+        let tmpdir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            cargo_toml: Some(
+                "[package]\nname = \"test-project\"\nversion = \"0.0.0\"\n\n\
+                 [ dependencies ] # shared by every snippet\n\
+                 greeting = { path = \"/nonexistent/greeting\" }\n",
+            ),
+            ..settings(&tmpdir)
+        };
+        let source = format!(
+            r#"
+// @dependency: greeting = {{ path = {:?} }}
+pub fn example() -> &'static str {{
+    greeting::hello()
+    //        ^?
+}}
+"#,
+            dependency.path().display().to_string()
+        );
+
+        // The template's table is extended rather than declared a second time
+        let project = Project::scaffold_with_code(settings, source.trim()).unwrap();
+        let result = project.twoslasher().unwrap();
+        assert!(result.bootstrap_error.is_none());
+        assert!(result.errors.is_empty());
+        assert_eq!(
+            result.queries[0].text.as_deref(),
+            Some("greeting\n\npub fn hello() -> &'static str\n\n---\n\nSays hello")
+        );
+    }
+
+    #[test]
+    fn test_pool_with_broken_workspace() {
+        let broken = "// @dependency: broken = { path = }\npub fn example() {}";
+        let messages = |result: &TwoSlash| {
+            result
+                .errors
+                .iter()
+                .map(|error| (error.id.clone(), error.rendered_message.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        // The snippet is analyzed without its dependency, and says why.
+        let tmpdir = TempDir::new().unwrap();
+        let mut projects = ProjectPool::new(settings(&tmpdir)).unwrap();
+        let result = projects.twoslash(broken).unwrap();
+        let errors = messages(&result);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "invalid_workspace");
+        assert!(errors[0].1.starts_with("Failed to load the project"));
+
+        // Which doesn't stick to the next snippet.
+        let result = projects.twoslash("pub fn example() {}").unwrap();
+        assert!(messages(&result).is_empty());
+
+        // A directive that isn't a dependency doesn't get that far.
+        let result = projects
+            .twoslash("// @dependency: broken = { path = \npub fn example() {}")
+            .unwrap();
+        assert_eq!(
+            messages(&result),
+            [(
+                "invalid_directive".to_string(),
+                "invalid dependency: \"broken = { path =\"".to_string()
+            )]
+        );
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut projects = ProjectPool::new(ProjectSettings {
+            strict: true,
+            ..settings(&tmpdir)
+        })
+        .unwrap();
+        assert!(projects.twoslash(broken).is_err());
+    }
+
+    #[test]
+    fn test_feature_and_cfg_directives() {
+        let dependency = greeting_crate();
+        let source = format!(
+            r#"
+// @dependency: greeting = {{ path = {:?} }}
+// @features: greeting/loud
+// @cfg: docsrs
+#[cfg(docsrs)]
+pub fn example() -> &'static str {{
+    greeting::shout()
+    //        ^?
+}}
+"#,
+            dependency.path().display().to_string()
+        );
+
+        let result = twoslash(&source);
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @"[]");
+        assert_snapshot!(serde_json::to_string_pretty(&result.queries).unwrap(), @r#"
+        [
+          {
+            "kind": "query",
+            "line": 3,
+            "offset": 14,
+            "text": "greeting\n\npub fn shout() -> &'static str",
+            "start": 64,
+            "length": 5
+          }
+        ]
+        "#);
+
+        // Without the feature there is no `shout`, so the query has no answer
+        let result = twoslash(&source.replace("// @features: greeting/loud\n", ""));
+        assert!(result.queries.is_empty());
+
+        let result = twoslash(&source.replace("// @cfg: docsrs\n", ""));
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @r#"
+        [
+          {
+            "renderedMessage": "code is inactive due to #[cfg] directives: docsrs is disabled",
+            "id": "inactive-code",
+            "category": "Info",
+            "code": 0,
+            "start": 0,
+            "length": 73,
+            "line": 0,
+            "character": 0
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_target_directive() {
+        let source = r#"
+// @target: wasm32-unknown-unknown
+#![no_std]
+
+#[cfg(target_arch = "wasm32")]
+pub fn example() -> usize {
+    core::mem::size_of::<usize>()
+}
+"#;
+        let result = twoslash(source);
+        let inactive = |result: &TwoSlash| result.errors.iter().any(|e| e.id == "inactive-code");
+        assert!(!inactive(&result));
+
+        let result =
+            twoslash(&source.replace("wasm32-unknown-unknown", "x86_64-unknown-linux-gnu"));
+        assert!(inactive(&result));
+
+        let result = twoslash(&source.replace("wasm32-unknown-unknown", "bogus"));
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @r#"
+        [
+          {
+            "renderedMessage": "unknown target `bogus`, analysing for the host",
+            "id": "workspace",
+            "category": "Warning",
+            "code": 0,
+            "start": 0,
+            "length": 0,
+            "line": 0,
+            "character": 0
+          },
+          {
+            "renderedMessage": "code is inactive due to #[cfg] directives: target_arch = \"wasm32\" is disabled",
+            "id": "inactive-code",
+            "category": "Info",
+            "code": 0,
+            "start": 12,
+            "length": 94,
+            "line": 2,
+            "character": 0
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_crate_type_directive() {
+        let source = r#"
+// @crate-type: bin
+fn main() {
+    let total = helper();
+}
+
+#[cfg(test)]
+fn helper() -> u32 {
+    1
+}
+"#;
+        let ids = |result: &TwoSlash| -> Vec<String> {
+            result.errors.iter().map(|error| error.id.clone()).collect()
+        };
+
+        let result = twoslash(source);
+        assert_eq!(ids(&result), ["inactive-code", "E0425"]);
+
+        let result = twoslash(&source.replace("bin", "test"));
+        assert_eq!(ids(&result), ["unused_variables"]);
+
+        let result = twoslash(&source.replace("bin", "example"));
+        assert_eq!(ids(&result), ["inactive-code", "E0425"]);
+    }
+
+    #[test]
+    fn test_proc_macro_server() {
+        let dependency = TempDir::new().unwrap();
+        std::fs::write(
+            dependency.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "hello-derive"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+proc-macro = true
+"#,
+        )
+        .unwrap();
+        std::fs::create_dir(dependency.path().join("src")).unwrap();
+        std::fs::write(
+            dependency.path().join("src/lib.rs"),
+            r#"
+use proc_macro::TokenStream;
+
+#[proc_macro_derive(Hello)]
+pub fn derive_hello(input: TokenStream) -> TokenStream {
+    let input = input.to_string();
+    let name = input.split_whitespace().skip_while(|t| *t != "struct").nth(1).unwrap();
+    let name = name.trim_end_matches(';');
+    format!("impl {name} {{ pub fn hello() -> &'static str {{ \"hello\" }} }}")
+        .parse()
+        .unwrap()
+}
+"#,
+        )
+        .unwrap();
+
+        let source = format!(
+            r#"
+// @dependency: hello-derive = {{ path = {:?} }}
+#[derive(hello_derive::Hello)]
+pub struct Greeter;
+
+pub fn example() -> &'static str {{
+    Greeter::hello()
+    //       ^?
+}}
+"#,
+            dependency.path().display().to_string()
+        );
+
+        let result = twoslash_with(&source, |settings| {
+            settings.proc_macro_server = ProcMacroServer::Sysroot
+        });
+        assert_snapshot!(serde_json::to_string_pretty(&result.queries).unwrap(), @r#"
+        [
+          {
+            "kind": "query",
+            "line": 5,
+            "offset": 13,
+            "text": "test_project::Greeter\n\npub fn hello() -> &'static str",
+            "start": 100,
+            "length": 5
+          }
+        ]
+        "#);
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @"[]");
+
+        let result = twoslash(&source);
+        assert_snapshot!(serde_json::to_string_pretty(&result.errors).unwrap(), @r#"
+        [
+          {
+            "renderedMessage": "proc-macro not yet built",
+            "id": "macro-error",
+            "category": "Info",
+            "code": 0,
+            "start": 9,
+            "length": 19,
+            "line": 0,
+            "character": 9
+          },
+          {
+            "renderedMessage": "no such associated item",
+            "id": "E0599",
+            "category": "Error",
+            "code": 0,
+            "start": 91,
+            "length": 14,
+            "line": 4,
+            "character": 4
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_bootstrap_error() {
+        let source = r#"
+// @dependency: missing = { path = "/nonexistent/missing" }
+pub fn example() {}
+"#;
+        let result = twoslash(source);
+        let error = result.bootstrap_error.unwrap();
+        assert_eq!(error.messages.len(), 1);
+        assert!(error.messages[0].starts_with("error: failed to get `missing` as a dependency"));
+        assert!(error.messages[0].ends_with("No such file or directory (os error 2)"));
+        assert!(error.stderr.contains(&error.messages[0]));
+
+        // The snippet's own errors are diagnostics, not bootstrap errors.
+        let result = twoslash("pub fn example() -> u32 { \"not a number\" }");
+        assert!(result.bootstrap_error.is_none());
+        assert_eq!(result.errors.len(), 1);
+    }
+
+    #[test]
+    fn test_server_answers_errors() {
+        let tmpdir = TempDir::new().unwrap();
+        let mut projects = ProjectPool::new(ProjectSettings {
+            strict: true,
+            ..settings(&tmpdir)
+        })
+        .unwrap();
+
+        let source = r#"
+// @dependency: missing = { path = "/nonexistent/missing" }
+pub fn example() {}
+"#;
+        let answer = crate::answer(
+            &mut projects,
+            source,
+            &crate::Format::Json,
+            PositionEncoding::Utf8,
+        )
+        .unwrap();
+        let answer: serde_json::Value = serde_json::from_str(&answer).unwrap();
+        let error = answer["error"].as_str().unwrap();
+        assert!(error.starts_with("failed to set up the project, could not resolve `missing`"));
+        assert_eq!(
+            answer["bootstrapError"]["unresolvedDependencies"],
+            serde_json::json!(["missing"])
+        );
+
+        // The server goes on to answer the next snippet.
+        let answer = crate::answer(
+            &mut projects,
+            "pub fn example() {}",
+            &crate::Format::Json,
+            PositionEncoding::Utf8,
+        );
+        let answer: serde_json::Value = serde_json::from_str(&answer.unwrap()).unwrap();
+        assert_eq!(answer["code"], "pub fn example() {}");
+    }
+
+    #[test]
+    fn test_bootstrap_error_strict() {
+        let tmpdir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            cargo_toml: Some("[package]\nname = \"test-project\"\nversion = \"0.0.0\"\nbad toml ["),
+            strict: true,
+            ..settings(&tmpdir)
+        };
+
+        let error = match Project::scaffold_with_code(settings, "pub fn example() {}") {
+            Ok(_) => panic!("expected the bootstrap to fail"),
+            Err(error) => error.downcast::<BootstrapError>().unwrap(),
+        };
+        assert_snapshot!(error.messages.join("\n---\n"), @"
+        error: key with no value, expected `=`
+         --> Cargo.toml:4:5
+          |
+        4 | bad toml [
+          |     ^
+        ");
+    }
+
+    #[test]
+    fn test_offline_vendored_dependencies() {
+        // A vendored copy of `greeting`, as `cargo vendor` would make it
+        let vendor = TempDir::new().unwrap();
+        let greeting = vendor.path().join("greeting");
+        std::fs::create_dir_all(greeting.join("src")).unwrap();
+        std::fs::write(
+            greeting.join("Cargo.toml"),
+            "[package]\nname = \"greeting\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            greeting.join("src/lib.rs"),
+            "/// Says hello\npub fn hello() -> &'static str { \"hello\" }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            greeting.join(".cargo-checksum.json"),
+            r#"{"files":{},"package":null}"#,
+        )
+        .unwrap();
+
+        let offline = OfflineSettings {
+            offline: true,
+            source: Some(LocalSource::Vendor(vendor.path().to_path_buf())),
+            ..OfflineSettings::default()
+        };
+        let source = r#"
+// @dependency: greeting = "0.1"
+pub fn example() -> &'static str {
+    greeting::hello()
+    //        ^?
+}
+"#;
+
+        let result = twoslash_with(source, |settings| settings.offline = offline.clone());
+        assert!(result.bootstrap_error.is_none());
+        assert_eq!(
+            result.queries[0].text.as_deref(),
+            Some("greeting\n\npub fn hello() -> &'static str\n\n---\n\nSays hello")
+        );
+
+        let result = twoslash_with(
+            &source.replace("greeting = \"0.1\"", "itoa = \"1\""),
+            |settings| settings.offline = offline.clone(),
+        );
+        let error = result.bootstrap_error.unwrap();
+        assert_eq!(error.unresolved_dependencies, ["itoa"]);
+        assert!(error.messages[0].starts_with("error: no matching package named `itoa` found"));
+
+        // Cargo's configuration can't name every directory
+        let quoted = vendor.path().join("vendor's");
+        std::fs::rename(vendor.path().join("greeting"), &quoted).unwrap();
+        let tmpdir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            offline: OfflineSettings {
+                source: Some(LocalSource::Vendor(quoted)),
+                ..offline.clone()
+            },
+            ..settings(&tmpdir)
+        };
+        let error = Project::scaffold_with_code(settings, source.trim())
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with("can't use "));
+    }
+
+    #[test]
+    fn test_cache_dir() {
+        let cache_dir = TempDir::new().unwrap();
+        let cache_path = cache_dir.path().to_str().unwrap();
+        let source = r#"
+pub fn example() -> usize {
+    let answer = 42;
+    answer
+}
+"#;
+        let entries = || -> Vec<_> {
+            std::fs::read_dir(cache_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect()
+        };
+
+        let twoslash_cached = |source: &str, crate_type: CrateType| {
+            let tmpdir = TempDir::new().unwrap();
+            let settings = ProjectSettings {
+                cache_dir: Some(cache_path),
+                crate_type,
+                ..settings(&tmpdir)
+            };
+            let project = Project::scaffold_with_code(settings, source.trim()).unwrap();
+            project.twoslasher().unwrap()
+        };
+
+        let first = twoslash_cached(source, CrateType::Lib);
+        assert_eq!(entries().len(), 1);
+        assert!(entries()[0].join("Cargo.lock").exists());
+        assert!(entries()[0].join("messages.json").exists());
+
+        // The resolved workspace is kept without the path of the project, which differs per run
+        let metadata = std::fs::read_to_string(entries()[0].join("metadata.json")).unwrap();
+        assert!(metadata.contains("{twoslash-root}"));
+
+        // The second run finds the project in the cache and comes to the same result
+        let second = twoslash_cached(source, CrateType::Lib);
+        assert_eq!(entries().len(), 1);
+        assert_eq!(snapshot(&first), snapshot(&second));
+
+        // A bin has the same manifest, but not the same targets
+        let bin = twoslash_cached(
+            "fn main() {\n    let _answer = 42;\n    //  ^?\n}",
+            CrateType::Bin,
+        );
+        assert_eq!(entries().len(), 2);
+        assert!(bin.errors.is_empty());
+        assert_eq!(bin.queries[0].text.as_deref(), Some("let _answer: i32"));
+
+        // A different manifest is cached separately
+        twoslash_cached(&format!("// @cfg: docsrs\n{}", source), CrateType::Lib);
+        assert_eq!(entries().len(), 3);
+        assert!(entries()
+            .iter()
+            .all(|entry| entry.join("Cargo.lock").exists()));
+    }
+
+    #[test]
+    fn test_timings() {
+        let source = r#"
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+"#;
+        let tmpdir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            timings: true,
+            ..settings(&tmpdir)
+        };
+        let project = Project::scaffold_with_code(settings, source.trim()).unwrap();
+        let timings = project.twoslasher().unwrap().timings.unwrap();
+        assert!(timings.bootstrap.is_some());
+        assert!(timings.load.is_some());
+
+        // Reusing the project skips straight to indexing the new snippet
+        let project = project.apply_change("fn sub(a: i32) -> i32 { a }".to_string());
+        let result = project.twoslasher().unwrap();
+        let timings = result.timings.unwrap();
+        assert!(timings.bootstrap.is_none());
+        assert!(timings.load.is_none());
+        assert!(result
+            .static_quick_infos
+            .iter()
+            .any(|info| info.target_string == "sub"));
+
+        // Timings are left out unless asked for
+        assert!(twoslash(source).timings.is_none());
+    }
+
+    #[test]
+    fn test_workspace() {
+        // A virtual workspace whose `app` uses `greeting` by path
+        let workspace = TempDir::new().unwrap();
+        let root = workspace.path();
+        std::fs::write(
+            root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"app\", \"greeting\"]\nresolver = \"2\"\n",
+        )
+        .unwrap();
+        std::fs::create_dir_all(root.join("app/src")).unwrap();
+        std::fs::write(
+            root.join("app/Cargo.toml"),
+            r#"
+[package]
+name = "app"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+greeting = { path = "../greeting" }
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("app/src/lib.rs"),
+            "pub fn greet(name: &str) -> String { format!(\"{} {}\", greeting::hello(), name) }",
+        )
+        .unwrap();
+        std::fs::create_dir_all(root.join("greeting/src")).unwrap();
+        std::fs::write(
+            root.join("greeting/Cargo.toml"),
+            "[package]\nname = \"greeting\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("greeting/src/lib.rs"),
+            "pub fn hello() -> &'static str { \"hello\" }",
+        )
+        .unwrap();
+
+        let tmpdir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            workspace: Some(WorkspaceSettings {
+                root: root.to_str().unwrap(),
+                package: None,
+            }),
+            ..settings(&tmpdir)
+        };
+        let source = r#"
+fn main() {
+    let _greeting = app::greet(greeting::hello());
+    //  ^?
+}
+"#;
+        let project = Project::scaffold_with_code(settings, source.trim()).unwrap();
+        let result = project.twoslasher().unwrap();
+        let errors: Vec<_> = result.errors.iter().map(|e| &e.rendered_message).collect();
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            result.queries[0].text.as_deref(),
+            Some("let _greeting: String")
+        );
+
+        // The snippet lives outside the workspace, so the workspace is left as it was
+        assert!(!root.join("app/examples").exists());
+
+        // Snippets for a target have the workspace checked for it
+        let rustc = std::process::Command::new("rustc")
+            .arg("-vV")
+            .output()
+            .unwrap();
+        let rustc = String::from_utf8(rustc.stdout).unwrap();
+        let host = rustc
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .unwrap();
+        let tmpdir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            workspace: Some(WorkspaceSettings {
+                root: root.to_str().unwrap(),
+                package: None,
+            }),
+            ..self::settings(&tmpdir)
+        };
+        let targeted = format!("// @target: {}\n{}", host, source.trim());
+        Project::scaffold_with_code(settings, &targeted).unwrap();
+        assert!(root.join("target").join(host).exists());
+
+        let project = project.apply_change(
+            "fn main() {\n    let _hello = greeting::hello();\n    //  ^?\n}".to_string(),
+        );
+        let result = project.twoslasher().unwrap();
+        assert_eq!(result.queries[0].text.as_deref(), Some("let _hello: &str"));
+
+        // Errors in the workspace are reported like those of a snippet's dependencies
+        std::fs::write(
+            root.join("greeting/src/lib.rs"),
+            "pub fn hello() -> &'static str { 42 }",
+        )
+        .unwrap();
+        let workspace_settings = |tmpdir| ProjectSettings {
+            workspace: Some(WorkspaceSettings {
+                root: root.to_str().unwrap(),
+                package: Some("app"),
+            }),
+            ..self::settings(tmpdir)
+        };
+        let tmpdir = TempDir::new().unwrap();
+        let project =
+            Project::scaffold_with_code(workspace_settings(&tmpdir), source.trim()).unwrap();
+        let error = project.twoslasher().unwrap().bootstrap_error.unwrap();
+        assert!(error.messages[0].starts_with("error[E0308]: mismatched types"));
+
+        let tmpdir = TempDir::new().unwrap();
+        let settings = ProjectSettings {
+            strict: true,
+            ..workspace_settings(&tmpdir)
+        };
+        let error = match Project::scaffold_with_code(settings, source.trim()) {
+            Ok(_) => panic!("expected the bootstrap to fail"),
+            Err(error) => error.downcast::<BootstrapError>().unwrap(),
+        };
+        assert!(error.messages[0].starts_with("error[E0308]: mismatched types"));
+    }
+
+    #[test]
+    fn test_markdown() {
+        let docs = TempDir::new().unwrap();
+        std::fs::create_dir(docs.path().join("guide")).unwrap();
+        std::fs::write(
+            docs.path().join("guide/intro.md"),
+            r#"# Intro
+
+```rust twoslash
+pub fn answer() -> i32 {
+    let answer = 42;
+    //  ^?
+    answer
+}
+```
+
+```rust
+not analyzed
+```
+
+```rust,ignore,twoslash
+not analyzed either
+```
+
+```rust,compile_fail,twoslash
+fn main() {
+    let answer: u8 = "42";
+}
+```
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            docs.path().join("notes.txt"),
+            "```rust twoslash
+```",
+        )
+        .unwrap();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut projects = ProjectPool::new(settings(&tmpdir)).unwrap();
+        let results =
+            twoslash_markdown(&mut projects, &[docs.path().to_path_buf()], |_, result| {
+                Ok(result)
+            })
+            .unwrap();
+
+        let intro = docs.path().join("guide/intro.md").display().to_string();
+        assert_eq!(results.keys().collect::<Vec<_>>(), [&intro]);
+        let blocks = &results[&intro];
+        assert_eq!(blocks.keys().collect::<Vec<_>>(), [&0, &3]);
+        assert_eq!(
+            blocks[&0].queries[0].text.as_deref(),
+            Some("let answer: i32")
+        );
+        assert!(blocks[&0].errors.is_empty());
+        assert!(!blocks[&3].errors.is_empty());
+    }
+
+    #[test]
+    fn test_mdbook() {
+        let chapter = r#"# Chapter
+
+```rust,twoslash
+pub struct Point {
+    pub x: i32,
+}
+
+pub fn origin() -> Point {
+    Point { x: "0" }
+}
+```
+"#;
+        let input = serde_json::json!([
+            {"root": "/book", "renderer": "html", "mdbook_version": "0.4.40", "config": {}},
+            {
+                "sections": [
+                    {"Chapter": {
+                        "name": "Chapter",
+                        "content": chapter,
+                        "number": [1],
+                        "sub_items": [
+                            {"Chapter": {"name": "Plain", "content": "No code", "sub_items": []}},
+                        ],
+                        "path": "chapter.md",
+                    }},
+                    "Separator",
+                ],
+                "__non_exhaustive": null,
+            },
+        ]);
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut projects = ProjectPool::new(settings(&tmpdir)).unwrap();
+        let output = mdbook::preprocess(&mut projects, &input.to_string(), 4).unwrap();
+        let book: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+        let sections = &book["sections"];
+        assert_eq!(sections[1], "Separator");
+        assert_eq!(sections[0]["Chapter"]["path"], "chapter.md");
+        assert_eq!(
+            sections[0]["Chapter"]["sub_items"][0]["Chapter"]["content"],
+            "No code"
+        );
+        let content = sections[0]["Chapter"]["content"].as_str().unwrap();
+        let html = content
+            .lines()
+            .skip_while(|line| !line.starts_with("<pre"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(content.starts_with("# Chapter\n\n<style>"));
+        assert_snapshot!(html, @r#"
+        <pre class="twoslash" style="tab-size: 4"><code>pub struct <span class="twoslash-hover">Point<span class="twoslash-popup">test_project
+
+        pub struct Point {
+            pub x: i32,
+        }</span></span> {
+            pub <span class="twoslash-hover">x<span class="twoslash-popup">test_project::Point
+
+        pub x: i32</span></span>: <span class="twoslash-hover">i32<span class="twoslash-popup">i32
+
+        ---
+
+        The 32-bit signed integer type.</span></span>,
+        }
+
+        pub fn <span class="twoslash-hover">origin<span class="twoslash-popup">test_project
+
+        pub fn origin() -&gt; Point</span></span>() -&gt; <span class="twoslash-hover">Point<span class="twoslash-popup">test_project
+
+        pub struct Point {
+            pub x: i32,
+        }</span></span> {
+            <span class="twoslash-hover">Point<span class="twoslash-popup">test_project
+
+        pub struct Point {
+            pub x: i32,
+        }</span></span> { <span class="twoslash-hover">x<span class="twoslash-popup">test_project::Point
+
+        pub x: i32</span></span>: <span class="twoslash-squiggle twoslash-error">&quot;0&quot;</span> }
+        <span class="twoslash-error twoslash-error">expected i32, found &amp;'static str</span>}
+        </code></pre>
+        "#);
+
+        // mdBook 0.5 calls the sections items
+        let input = serde_json::json!([
+            {"root": "/book", "renderer": "html", "mdbook_version": "0.5.0", "config": {}},
+            {"items": [{"Chapter": {"name": "Chapter", "content": chapter, "sub_items": []}}]},
+        ]);
+        let output = mdbook::preprocess(&mut projects, &input.to_string(), 4).unwrap();
+        let book: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let content = book["items"][0]["Chapter"]["content"].as_str().unwrap();
+        assert!(content.contains("<pre class=\"twoslash\""));
+
+        // A book without either isn't passed on with its snippets left as they are
+        let input = serde_json::json!([{"mdbook_version": "9.0.0"}, {"chapters": []}]);
+        let error = mdbook::preprocess(&mut projects, &input.to_string(), 4).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the book from mdBook 9.0.0 has no `items` or `sections` to preprocess"
+        );
+    }
+
+    #[test]
+    fn test_html() {
+        let result = twoslash(
+            r#"
+pub fn answer() -> u8 {
+    let n = 42;
+    //  ^?
+    "n"
+}
+"#,
+        );
+        assert_snapshot!(html::render(&result, 4), @r#"
+        <pre class="twoslash" style="tab-size: 4"><code>pub fn <span class="twoslash-hover">answer<span class="twoslash-popup">test_project
+
+        pub fn answer() -&gt; u8</span></span>() -&gt; <span class="twoslash-hover">u8<span class="twoslash-popup">u8
+
+        ---
+
+        The 8-bit unsigned integer type.</span></span> {
+            let <span class="twoslash-hover">n<span class="twoslash-popup">let n: i32</span></span> = 42;
+        <span class="twoslash-query">        <span class="twoslash-query-box">let n: i32</span></span>    <span class="twoslash-squiggle twoslash-error">&quot;n&quot;</span>
+        <span class="twoslash-error twoslash-error">expected u8, found &amp;'static str</span>}
+        </code></pre>
+        "#);
+
+        let document = html::document(&result, 4);
+        assert!(document.starts_with("<!DOCTYPE html>"));
+        assert!(document.contains(html::STYLE));
+
+        // Problems setting up the snippet aren't about its code, so they come before it
+        let result = twoslash("// @edition: 2025\npub fn example() {}");
+        assert_snapshot!(html::render(&result, 4), @r#"
+        <span class="twoslash-error twoslash-error">invalid edition: &quot;2025&quot;</span>
+        <pre class="twoslash" style="tab-size: 4"><code><span class="twoslash-hover">pub fn example() {}<span class="twoslash-popup">extern crate test_project</span></span>
+        </code></pre>
+        "#);
+
+        // Boxes are as far in as the token on screen, which tabs and wide characters push along
+        let result = twoslash("pub fn example() {\n\tlet (é, n) = ('é', 1);\n\t//      ^?\n}");
+        let query = format!("<span class=\"twoslash-query\">{}<span", " ".repeat(12));
+        assert!(html::render(&result, 4).contains(&query));
+    }
+
+    #[test]
+    fn test_html_crossing_spans() {
+        let error = |start: u32, length: u32, category: DiagnosticCategory| Error {
+            rendered_message: category.name().to_string(),
+            id: "test".to_string(),
+            category,
+            code: 0,
+            start,
+            length,
+            line: 0,
+            character: start,
+            fixes: None,
+            setup: false,
+        };
+        let result = TwoSlash {
+            code: "let crossing = 1;".to_string(),
+            extension: "rs".to_string(),
+            highlights: vec![],
+            static_quick_infos: vec![],
+            queries: vec![],
+            tags: vec![],
+            errors: vec![
+                error(4, 8, DiagnosticCategory::Error),
+                error(8, 8, DiagnosticCategory::Warning),
+            ],
+            adjustments: None,
+            bootstrap_error: None,
+            timings: None,
+            playground_url: String::new(),
+        };
+
+        assert_snapshot!(html::render(&result, 4), @r#"
+        <pre class="twoslash" style="tab-size: 4"><code>let <span class="twoslash-squiggle twoslash-error">cros<span class="twoslash-squiggle twoslash-warning">sing</span></span><span class="twoslash-squiggle twoslash-warning"> = 1</span>;
+        <span class="twoslash-error twoslash-error">error</span><span class="twoslash-error twoslash-warning">warning</span></code></pre>
+        "#);
+    }
+
+    #[test]
+    fn test_terminal() {
+        let result = twoslash(
+            r#"
+pub fn answer() -> u8 {
+    let n = 42;
+    //  ^?
+    let total: u8 = "n";
+    total
+}
+"#,
+        );
+        assert_snapshot!(terminal::render(&result, false, 4), @r#"
+        1 | pub fn answer() -> u8 {
+        2 |     let n = 42;
+          |         ^ let n: i32
+        3 |     let total: u8 = "n";
+          |                     ^^^ error: expected u8, found &'static str
+        4 |     total
+        5 | }
+        "#);
+        assert!(terminal::render(&result, true, 4).contains("\x1b[1;31m"));
+
+        // Tabs are expanded, so that markers line up under the code they point at
+        let result = twoslash("pub fn answer() -> u8 {\n\tlet n = 42;\n\t//  ^?\n\t\"n\"\n}");
+        assert_snapshot!(terminal::render(&result, false, 2), @r#"
+        1 | pub fn answer() -> u8 {
+        2 |   let n = 42;
+          |       ^ let n: i32
+        3 |   "n"
+          |   ^^^ error: expected u8, found &'static str
+        4 | }
+        "#);
+
+        // Problems setting up the snippet have no place in the code
+        let result = twoslash("// @edition: 2025\npub fn example() {}");
+        assert_snapshot!(terminal::render(&result, false, 4), @r#"
+        error: invalid edition: "2025"
+        1 | pub fn example() {}
+        "#);
+    }
+
+    #[test]
+    fn test_nodes() {
+        let source = r#"
+// @annotate: left
+pub struct Point { pub x: i32 }
+pub fn norm(p: Point) -> u8 {
+    let n = p.x;
+    //  ^?
+    p.x
+//     ^|
+}
+"#;
+        let tmpdir = TempDir::new().unwrap();
+        let project = Project::scaffold_with_code(settings(&tmpdir), source.trim()).unwrap();
+        let result = project.twoslasher().unwrap();
+        let nodes = nodes::render(&result, |tag| project.tag_line(tag), PositionEncoding::Utf8);
+        let mut nodes = serde_json::to_value(&nodes).unwrap();
+        // Completions come in no particular order, so only the field is kept.
+        for node in nodes["nodes"].as_array_mut().unwrap() {
+            if let Some(completions) = node.get_mut("completions") {
+                completions
+                    .as_array_mut()
+                    .unwrap()
+                    .retain(|completion| completion["name"] == "x");
+            }
+        }
+        assert_snapshot!(serde_json::to_string_pretty(&nodes).unwrap(), @r#"
+        {
+          "code": "pub struct Point { pub x: i32 }\npub fn norm(p: Point) -> u8 {\n    let n = p.x;\n    p.x\n}",
+          "nodes": [
+            {
+              "start": 0,
+              "length": 0,
+              "line": 0,
+              "character": 0,
+              "type": "tag",
+              "name": "annotate",
+              "text": "left"
+            },
+            {
+              "start": 11,
+              "length": 5,
+              "line": 0,
+              "character": 11,
+              "type": "hover",
+              "target": "Point",
+              "text": "test_project\n\npub struct Point {\n    pub x: i32,\n}"
+            },
+            {
+              "start": 23,
+              "length": 1,
+              "line": 0,
+              "character": 23,
+              "type": "hover",
+              "target": "x",
+              "text": "test_project::Point\n\npub x: i32"
+            },
+            {
+              "start": 26,
+              "length": 3,
+              "line": 0,
+              "character": 26,
+              "type": "hover",
+              "target": "i32",
+              "text": "i32\n\n---\n\nThe 32-bit signed integer type."
+            },
+            {
+              "start": 39,
+              "length": 4,
+              "line": 1,
+              "character": 7,
+              "type": "hover",
+              "target": "norm",
+              "text": "test_project\n\npub fn norm(p: Point) -> u8"
+            },
+            {
+              "start": 44,
+              "length": 1,
+              "line": 1,
+              "character": 12,
+              "type": "hover",
+              "target": "p",
+              "text": "p: Point"
+            },
+            {
+              "start": 47,
+              "length": 5,
+              "line": 1,
+              "character": 15,
+              "type": "hover",
+              "target": "Point",
+              "text": "test_project\n\npub struct Point {\n    pub x: i32,\n}"
+            },
+            {
+              "start": 57,
+              "length": 2,
+              "line": 1,
+              "character": 25,
+              "type": "hover",
+              "target": "u8",
+              "text": "u8\n\n---\n\nThe 8-bit unsigned integer type."
+            },
+            {
+              "start": 70,
+              "length": 1,
+              "line": 2,
+              "character": 8,
+              "type": "hover",
+              "target": "n",
+              "text": "let n: i32"
+            },
+            {
+              "start": 70,
+              "length": 1,
+              "line": 2,
+              "character": 8,
+              "type": "query",
+              "target": "n",
+              "text": "let n: i32"
+            },
+            {
+              "start": 74,
+              "length": 1,
+              "line": 2,
+              "character": 12,
+              "type": "hover",
+              "target": "p",
+              "text": "p: Point"
+            },
+            {
+              "start": 76,
+              "length": 1,
+              "line": 2,
+              "character": 14,
+              "type": "hover",
+              "target": "x",
+              "text": "test_project::Point\n\npub x: i32"
+            },
+            {
+              "start": 83,
+              "length": 1,
+              "line": 3,
+              "character": 4,
+              "type": "hover",
+              "target": "p",
+              "text": "p: Point"
+            },
+            {
+              "start": 85,
+              "length": 1,
+              "line": 3,
+              "character": 6,
+              "type": "hover",
+              "target": "x",
+              "text": "test_project::Point\n\npub x: i32"
+            },
+            {
+              "start": 85,
+              "length": 0,
+              "line": 3,
+              "character": 6,
+              "type": "completion",
+              "completions": [
+                {
+                  "name": "x"
+                }
+              ],
+              "completionsPrefix": ""
+            },
+            {
+              "start": 85,
+              "length": 1,
+              "line": 3,
+              "character": 6,
+              "type": "error",
+              "id": "E0308",
+              "code": 0,
+              "level": "error",
+              "text": "expected u8, found i32"
+            }
+          ],
+          "meta": {
+            "extension": ".rs",
+            "positionQueries": [
+              70
+            ],
+            "positionCompletions": [
+              85
+            ],
+            "positionHighlights": []
+          }
+        }
+        "#);
+
+        // The server answers with the same nodes
+        let tmpdir = TempDir::new().unwrap();
+        let mut projects = ProjectPool::new(settings(&tmpdir)).unwrap();
+        let answer = crate::answer(
+            &mut projects,
+            source.trim(),
+            &crate::Format::Nodes,
+            PositionEncoding::Utf8,
+        )
+        .unwrap();
+        let answer: serde_json::Value = serde_json::from_str(&answer).unwrap();
+        assert_eq!(answer["code"], nodes["code"]);
+        let kinds = |nodes: &serde_json::Value| -> Vec<serde_json::Value> {
+            nodes["nodes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|node| node["type"].clone())
+                .collect()
+        };
+        assert_eq!(kinds(&answer), kinds(&nodes));
+    }
+
+    #[test]
+    fn test_position_encoding() {
+        let source = r#"
+pub fn love() -> usize {
+    let heart = "💖"; let n = heart.len();
+    n
+//  ^?
+}
+"#;
+        // The heart is 4 bytes, 2 UTF-16 code units and 1 character, so the `heart` after it
+        // starts 2 or 3 earlier, as does everything on the lines below.
+        for (encoding, heart, n) in [
+            (PositionEncoding::Utf8, (57, 5, 32), (74, 1, 4)),
+            (PositionEncoding::Utf16, (55, 5, 30), (72, 1, 4)),
+            (PositionEncoding::Utf32, (54, 5, 29), (71, 1, 4)),
+        ] {
+            let mut result = twoslash(source);
+            result.encode_positions(encoding);
+            let info = result
+                .static_quick_infos
+                .iter()
+                .filter(|info| info.target_string == "heart")
+                .max_by_key(|info| info.start)
+                .unwrap();
+            assert_eq!((info.start, info.length, info.character), heart);
+            let query = &result.queries[0];
+            assert_eq!((query.start, query.length, query.offset), n);
+        }
+    }
+
+    #[test]
+    fn test_windows_line_endings() {
+        let source = [
+            "// @edition: 2021",
+            "pub struct Hidden;",
+            "// ---cut---",
+            "pub fn answer() -> u8 {",
+            "\tlet n = 42;",
+            "\t//  ^?",
+            "\tn",
+            "}",
+        ]
+        .join("\r\n");
+        let result = twoslash(&source);
+
+        assert_eq!(
+            result.code,
+            "pub fn answer() -> u8 {\r\n\tlet n = 42;\r\n\tn\r\n}"
+        );
+        assert!(result.errors.is_empty());
+        let query = &result.queries[0];
+        assert_eq!(query.text.as_deref(), Some("let n: u8"));
+        assert_eq!(
+            &result.code[query.start as usize..][..query.length as usize],
+            "n"
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let docs = TempDir::new().unwrap();
+        std::fs::write(
+            docs.path().join("guide.md"),
+            r#"# Guide
+
+```rust twoslash
+pub fn fine() -> i32 {
+    let n = 1;
+    //  ^?
+    n
+}
+```
+
+```rust twoslash
+pub fn broken() -> u8 {
+    "1"
+}
+```
+
+```rust,compile_fail,twoslash
+pub fn works() -> u8 {
+    1
+}
+```
+
+```rust twoslash
+pub fn answer() -> u8 {
+    // ^?
+    42
+}
+// ---cut---
+pub fn shown() -> u8 {
+    answer()
+}
+```
+
+```rust twoslash
+pub fn unknown() -> u8 {
+    missing
+//  ^?
+}
+```
+
+```rust twoslash
+// ^?
+pub fn first() {}
+```
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            docs.path().join("Cargo.toml"),
+            "[package]\nname = \"doubler\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        std::fs::create_dir(docs.path().join("src")).unwrap();
+        std::fs::write(
+            docs.path().join("src/lib.rs"),
+            r#"/// Doubles a number.
+///
+/// ```twoslash
+/// pub fn four() -> u8 {
+///     "4"
+/// }
+/// ```
+pub fn double(x: u8) -> u8 {
+    x * 2
+}
+
+/// Halves a number.
+///
+/// ```twoslash
+/// # use doubler::{double, half};
+/// let two = half(double(2));
+/// //  ^?
+/// let three: u8 = "3";
+/// ```
+pub fn half(x: u8) -> u8 {
+    x / 2
+}
+"#,
+        )
+        .unwrap();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut projects = ProjectPool::new(settings(&tmpdir)).unwrap();
+        let problems = check::check(&mut projects, &[docs.path().to_path_buf()]).unwrap();
+        let problems = problems
+            .iter()
+            .map(|problem| {
+                let file = problem.file.strip_prefix(docs.path()).unwrap();
+                format!("{}:{}: {}", file.display(), problem.line, problem.message)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_snapshot!(problems, @"
+        guide.md:13: error: expected u8, found &'static str
+        guide.md:18: expected an error in a `compile_fail` snippet
+        guide.md:24: the query points outside the cut
+        guide.md:36: error: no such value in this scope
+        guide.md:36: the query has no answer
+        guide.md:42: the query has no line to point at
+        src/lib.rs:5: error: expected u8, found &'static str
+        src/lib.rs:18: error: expected u8, found &'static str
+        ");
+    }
+
+    #[test]
+    fn test_rustdoc() {
+        let krate = TempDir::new().unwrap();
+        std::fs::write(
+            krate.path().join("Cargo.toml"),
+            "[package]\nname = \"shapes\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        std::fs::create_dir_all(krate.path().join("src")).unwrap();
+        std::fs::write(
+            krate.path().join("src/lib.rs"),
+            r#"//! Shapes.
+//!
+//! ```text
+//! not a doctest
+//! ```
+
+pub mod square;
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            krate.path().join("src/square.rs"),
+            r#"/// A square.
+pub struct Square(pub u32);
+
+impl Square {
+    /// The area of the square.
+    ///
+    /// ```
+    /// # use shapes::square::Square;
+    /// let area = Square(2).area();
+    /// //  ^?
+    /// ```
+    ///
+    /// ```ignore
+    /// not analyzed
+    /// ```
+    ///
+    /// ```
+    /// let side = 3;
+    /// # let side = side + 1;
+    /// let area = shapes::square::Square(side).area();
+    /// //  ^?
+    /// ```
+    pub fn area(&self) -> u32 {
+        self.0 * self.0
+    }
+}
+"#,
+        )
+        .unwrap();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut projects = ProjectPool::new(settings(&tmpdir)).unwrap();
+        let results =
+            rustdoc::twoslash_crate(&mut projects, krate.path(), |_, result| Ok(result)).unwrap();
+
+        assert_eq!(
+            results.keys().collect::<Vec<_>>(),
+            ["shapes::square::Square::area"]
+        );
+        let examples = &results["shapes::square::Square::area"];
+        assert_eq!(examples.keys().collect::<Vec<_>>(), [&0, &2]);
+        let example = &examples[&0];
+        assert_eq!(example.code, "let area = Square(2).area();\n");
+        // The hidden `use` resolves through the crate dependency
+        assert!(example.bootstrap_error.is_none());
+        let errors: Vec<_> = example
+            .errors
+            .iter()
+            .map(|error| error.rendered_message.as_str())
+            .collect();
+        assert_eq!(errors, ["unused variable"]);
+        assert_eq!(example.queries[0].text.as_deref(), Some("let area: u32"));
+
+        // Hidden lines between shown ones are taken out too, and what follows moves up
+        let example = &examples[&2];
+        assert_eq!(
+            example.code,
+            "let side = 3;\nlet area = shapes::square::Square(side).area();\n"
+        );
+        assert_eq!(example.queries[0].line, 2);
+        assert_eq!(example.queries[0].text.as_deref(), Some("let area: u32"));
+        for info in &example.static_quick_infos {
+            let (start, end) = (info.start as usize, (info.start + info.length) as usize);
+            assert_eq!(info.target_string, example.code[start..end]);
+        }
+    }
 }
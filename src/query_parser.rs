@@ -1,78 +1,197 @@
 use lazy_static::lazy_static;
 use regex::Regex;
 
-use ra_ide::{LineCol, LineIndex, TextSize};
+use ra_ide::TextSize;
 
 use crate::twoslash::QueryKind;
 
+/// A marker kind, the regex that recognizes it, and how to map the caret column to the query column.
+type Parser = (MarkerKind, Regex, fn(u32) -> u32);
+
 lazy_static! {
-    static ref PARSERS: Vec<(QueryKind, Regex, fn(u32) -> u32)> = vec![
+    static ref PARSERS: Vec<Parser> = vec![
         (
-            QueryKind::Query,
+            MarkerKind::Query,
             Regex::new(r#"^\s*//\s*(?P<caret>\^)\?"#).unwrap(),
             std::convert::identity,
         ),
         (
-            QueryKind::Completions,
+            MarkerKind::Completions,
             Regex::new(r#"^\s*//\s*(?P<caret>\^)\|"#).unwrap(),
             |n| { n - 1 }
         ),
+        (
+            MarkerKind::ConstEval,
+            Regex::new(r#"^\s*//\s*(?P<caret>\^)="#).unwrap(),
+            std::convert::identity,
+        ),
     ];
+    static ref ASSIST_PARSER: Regex =
+        Regex::new(r#"^\s*//\s*(?P<caret>\^)\s*@assist:\s*(?P<assist>\w+)\s*$"#).unwrap();
+    static ref DIRECTIVE_PARSER: Regex =
+        Regex::new(r#"^\s*//\s*@(?P<name>[\w-]+)(?::\s*(?P<value>.*?))?\s*$"#).unwrap();
+}
+
+/// What a marker asks about the token its caret points at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkerKind {
+    Query,
+    Completions,
+    ConstEval,
+    /// A preview of the rust-analyzer assist with this id
+    Assist(String),
+}
+
+impl MarkerKind {
+    pub fn query_kind(&self) -> QueryKind {
+        match self {
+            MarkerKind::Query => QueryKind::Query,
+            MarkerKind::Completions => QueryKind::Completions,
+            MarkerKind::ConstEval => QueryKind::ConstEval,
+            MarkerKind::Assist(_) => QueryKind::Assist,
+        }
+    }
+}
+
+/// A `// @name: value` line, which configures how the snippet is analyzed.
+pub struct Directive {
+    pub name: String,
+    pub value: Option<String>,
+    /// The line of the directive in the original source
+    pub line: u32,
 }
 
 pub struct ParseResult {
     pub code: String,
-    pub queries: Vec<(QueryKind, TextSize)>,
+    /// The markers, in the order they are in the source
+    pub queries: Vec<(MarkerKind, TextSize)>,
+    pub directives: Vec<Directive>,
+    /// The line of the original source each line of `code` comes from
+    pub source_lines: Vec<u32>,
+    /// The markers with no line of code above them to point at, and their lines in the original
+    /// source
+    pub unplaced: Vec<(MarkerKind, u32)>,
 }
 
-pub fn find_queries(src: &str) -> ParseResult {
+/// Finds the markers and directives of a snippet, and removes their lines from the code. Line
+/// endings are kept as they are, apart from the one ending the code.
+///
+/// The caret of a marker points at whatever is shown above it, so columns are counted the way an
+/// editor shows them: a character per column, and tabs advancing to the next multiple of
+/// `tab_width`.
+pub fn find_queries(src: &str, tab_width: u32) -> ParseResult {
     let mut queries = vec![];
-    let mut removed_lines = 0;
-    let mut lines = vec![];
+    let mut directives = vec![];
+    let mut code = String::new();
+    let mut source_lines = vec![];
+    let mut unplaced = vec![];
+    // The last line kept in the code, and where it starts
+    let mut above: Option<(&str, usize)> = None;
+    // Where in the code a caret at `column` points. Markers with no line above point nowhere.
+    let position = |above: Option<(&str, usize)>, column: u32| {
+        let (line, start) = above?;
+        Some(TextSize::from(
+            start as u32 + offset_at_column(line, column, tab_width),
+        ))
+    };
 
-    for (i, line) in src.lines().enumerate() {
+    for (i, line_with_ending) in src.split_inclusive('\n').enumerate() {
+        let line = line_with_ending
+            .strip_suffix('\n')
+            .map_or(line_with_ending, |line| {
+                line.strip_suffix('\r').unwrap_or(line)
+            });
         let mut skip_line = false;
 
-        // Check for query markers (^? and ^|)
+        // Check for query markers (^?, ^| and ^=)
         for (kind, parser, transform_col) in PARSERS.iter() {
             if let Some(capture) = parser.captures(line) {
-                let col = capture.name("caret").unwrap().start() as u32;
-                let col = transform_col(col);
-                queries.push((
-                    *kind,
-                    LineCol {
-                        line: (i - removed_lines - 1) as u32,
-                        col,
-                    },
-                ));
+                let caret = capture.name("caret").unwrap().start();
+                let column = transform_col(width(&line[..caret], tab_width));
+                match position(above, column) {
+                    Some(position) => queries.push((kind.clone(), position)),
+                    None => unplaced.push((kind.clone(), i as u32)),
+                }
                 skip_line = true;
-                removed_lines += 1;
             }
         }
 
+        // Check for assist markers (^ @assist: <id>)
+        if let Some(capture) = ASSIST_PARSER.captures(line) {
+            let caret = capture.name("caret").unwrap().start();
+            let kind = MarkerKind::Assist(capture["assist"].to_string());
+            match position(above, width(&line[..caret], tab_width)) {
+                Some(position) => queries.push((kind, position)),
+                None => unplaced.push((kind, i as u32)),
+            }
+            skip_line = true;
+        }
+
+        // Check for directives (// @name: value)
+        if let Some(capture) = DIRECTIVE_PARSER.captures(line) {
+            directives.push(Directive {
+                name: capture["name"].to_string(),
+                value: capture
+                    .name("value")
+                    .map(|value| value.as_str().to_string()),
+                line: i as u32,
+            });
+            skip_line = true;
+        }
+
         if !skip_line {
-            lines.push(line);
+            above = Some((line, code.len()));
+            code.push_str(line_with_ending);
+            source_lines.push(i as u32);
         }
     }
 
-    let new_text = lines.join("\n");
-    let line_index = LineIndex::new(&new_text);
-    let queries = queries
-        .into_iter()
-        .map(|(kind, line_col)| (kind, line_index.offset(line_col).unwrap()))
-        .collect();
+    // The code ends with its last line, not a line ending.
+    let trimmed = code.strip_suffix('\n').unwrap_or(&code);
+    let trimmed = trimmed.strip_suffix('\r').unwrap_or(trimmed);
+    code.truncate(trimmed.len());
 
     ParseResult {
-        code: new_text,
+        code,
         queries,
+        directives,
+        source_lines,
+        unplaced,
+    }
+}
+
+/// How many columns `text` takes up when shown, with tabs advancing to the next multiple of
+/// `tab_width`.
+pub fn width(text: &str, tab_width: u32) -> u32 {
+    text.chars()
+        .fold(0, |column, c| next_column(column, c, tab_width))
+}
+
+/// The byte offset in `line` of what is shown at `column`. Columns past the end of the line count
+/// on from its end.
+fn offset_at_column(line: &str, column: u32, tab_width: u32) -> u32 {
+    let mut line_width = 0;
+    for (offset, c) in line.char_indices() {
+        let next = next_column(line_width, c, tab_width);
+        if column < next {
+            return offset as u32;
+        }
+        line_width = next;
+    }
+    line.len() as u32 + (column - line_width)
+}
+
+/// The column after `c`, when `c` is shown at `column`.
+pub fn next_column(column: u32, c: char, tab_width: u32) -> u32 {
+    match c {
+        '\t' => (column / tab_width.max(1) + 1) * tab_width.max(1),
+        _ => column + 1,
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::twoslash::QueryKind;
-
-    use super::find_queries;
+    use super::{find_queries, MarkerKind};
 
     #[test]
     fn test_find_queries() {
@@ -94,7 +213,7 @@ foo {
 }
 "#
         .trim();
-        let result = find_queries(src);
+        let result = find_queries(src, 4);
 
         let pretty_queries: Vec<_> = result
             .queries
@@ -107,12 +226,145 @@ foo {
             .collect();
 
         let expected = vec![
-            (QueryKind::Query, "bar"),
-            (QueryKind::Query, "oob"),
-            (QueryKind::Completions, ".b\n"),
-            (QueryKind::Completions, "fo\n"),
+            (MarkerKind::Query, "bar"),
+            (MarkerKind::Query, "oob"),
+            (MarkerKind::Completions, ".b\n"),
+            (MarkerKind::Completions, "fo\n"),
         ];
 
         assert_eq!(pretty_queries, expected);
     }
+
+    #[test]
+    fn test_find_assists() {
+        let src = r#"
+let total = 1 + 2;
+//  ^ @assist: inline_local_variable
+total
+"#
+        .trim();
+        let result = find_queries(src, 4);
+
+        assert_eq!(result.code, "let total = 1 + 2;\ntotal");
+        assert_eq!(result.source_lines, [0, 2]);
+        assert_eq!(
+            result.queries,
+            vec![(
+                MarkerKind::Assist("inline_local_variable".to_string()),
+                4.into()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_markers_in_source_order() {
+        let src = r#"
+let total = 1 + 2;
+//  ^ @assist: inline_local_variable
+//          ^?
+"#
+        .trim();
+        let result = find_queries(src, 4);
+
+        assert_eq!(
+            result.queries,
+            vec![
+                (
+                    MarkerKind::Assist("inline_local_variable".to_string()),
+                    4.into()
+                ),
+                (MarkerKind::Query, 12.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_directives() {
+        let src = r#"
+// @edition: 2024
+// @flag
+let answer = 42;
+//  ^?
+"#
+        .trim();
+        let result = find_queries(src, 4);
+
+        assert_eq!(result.code, "let answer = 42;");
+        assert_eq!(result.queries, vec![(MarkerKind::Query, 4.into())]);
+
+        let directives: Vec<_> = result
+            .directives
+            .iter()
+            .map(|d| (d.name.as_str(), d.value.as_deref(), d.line))
+            .collect();
+        assert_eq!(
+            directives,
+            vec![("edition", Some("2024"), 0), ("flag", None, 1)]
+        );
+    }
+
+    #[test]
+    fn test_marker_without_line_above() {
+        let src = r#"
+// @edition: 2021
+//  ^?
+// ^ @assist: add_explicit_type
+let answer = 42;
+//  ^?
+"#
+        .trim();
+        let result = find_queries(src, 4);
+
+        assert_eq!(result.code, "let answer = 42;");
+        assert_eq!(result.queries, vec![(MarkerKind::Query, 4.into())]);
+        assert_eq!(
+            result.unplaced,
+            vec![
+                (MarkerKind::Query, 1),
+                (MarkerKind::Assist("add_explicit_type".to_string()), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_crlf() {
+        let src = "// @edition: 2021\r\nlet answer = 42;\r\n//  ^?\r\nanswer\r\n//^?\r\n";
+        let result = find_queries(src, 4);
+
+        assert_eq!(result.code, "let answer = 42;\r\nanswer");
+        assert_eq!(
+            result.queries,
+            vec![
+                (MarkerKind::Query, 4.into()),
+                (MarkerKind::Query, 20.into())
+            ]
+        );
+        assert_eq!(result.source_lines, [1, 3]);
+        assert_eq!(result.directives[0].value.as_deref(), Some("2021"));
+    }
+
+    #[test]
+    fn test_tabs() {
+        // A marker indented with a tab too lines up for any tab width.
+        for tab_width in [2, 4, 8] {
+            let result = find_queries("\tlet answer = 42;\n\t//  ^?", tab_width);
+            assert_eq!(result.queries, vec![(MarkerKind::Query, 5.into())]);
+        }
+
+        // A marker indented with spaces lines up for the tab width it was written with.
+        let src = "\tlet answer = 42;\n    //  ^?";
+        assert_eq!(
+            find_queries(src, 4).queries,
+            vec![(MarkerKind::Query, 5.into())]
+        );
+        assert_eq!(
+            find_queries(src, 8).queries,
+            vec![(MarkerKind::Query, 1.into())]
+        );
+
+        // Characters take up a column each, however many bytes they are.
+        let result = find_queries("let s = \"💖\"; let n = 1;\n//               ^?", 4);
+        let n = result.code.find('n').unwrap() as u32;
+        assert_eq!(result.queries, vec![(MarkerKind::Query, n.into())]);
+    }
 }
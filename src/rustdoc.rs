@@ -0,0 +1,694 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use ra_ide::Edition;
+use ra_syntax::ast::{self, DocCommentIter, HasDocComments, HasModuleItem, HasName};
+use ra_syntax::{AstNode, SyntaxNode};
+
+use crate::markdown::{code_blocks, CodeBlock};
+use crate::project::{Project, ProjectPool, WorkspaceSettings};
+use crate::twoslash::TwoSlash;
+
+/// The words rustdoc understands in the info string of a code block. Any other word names a
+/// language, and makes the block something other than Rust.
+const RUSTDOC_ATTRIBUTES: &[&str] = &[
+    "rust",
+    "ignore",
+    "no_run",
+    "compile_fail",
+    "should_panic",
+    "test_harness",
+    "standalone_crate",
+    "edition2015",
+    "edition2018",
+    "edition2021",
+    "edition2024",
+    "twoslash",
+];
+
+/// The code blocks of the doc comments of a Rust source file. Each run of `///` or `//!` lines is
+/// a markdown document of its own; the blocks are numbered across the file, and their lines are
+/// those of the file. Their ranges are in the markdown of their doc comment.
+pub fn doc_code_blocks(source: &str) -> Vec<CodeBlock> {
+    let mut blocks = vec![];
+    let mut comment: Vec<&str> = vec![];
+    let mut comment_start = 0;
+    let mut comment_prefix = "";
+    for (i, line) in source.lines().chain([""]).enumerate() {
+        let doc = doc_line(line);
+        if comment_prefix.is_empty() || doc.is_none_or(|(prefix, _)| prefix != comment_prefix) {
+            for mut block in code_blocks(&comment.join("\n")) {
+                block.index = blocks.len();
+                block.line += comment_start;
+                blocks.push(block);
+            }
+            comment.clear();
+            comment_start = i as u32;
+            comment_prefix = "";
+        }
+        if let Some((prefix, text)) = doc {
+            comment_prefix = prefix;
+            comment.push(text);
+        }
+    }
+    blocks
+}
+
+/// Splits a `///` or `//!` line into its prefix and its text.
+fn doc_line(line: &str) -> Option<(&'static str, &str)> {
+    let line = line.trim_start();
+    if line.starts_with("////") {
+        return None;
+    }
+    ["///", "//!"].into_iter().find_map(|prefix| {
+        let text = line.strip_prefix(prefix)?;
+        Some((prefix, text.strip_prefix(' ').unwrap_or(text)))
+    })
+}
+
+/// Whether a doc comment block is a doctest rustdoc would compile. Unlike in markdown, blocks
+/// without a language are Rust.
+pub fn is_doctest(block: &CodeBlock) -> bool {
+    !block.has_attribute("ignore")
+        && block
+            .attributes
+            .iter()
+            .all(|attribute| attribute == "rs" || RUSTDOC_ATTRIBUTES.contains(&attribute.as_str()))
+}
+
+/// Whether a doc comment block is Rust code tagged `twoslash` that should be analyzed.
+pub fn should_twoslash(block: &CodeBlock) -> bool {
+    is_doctest(block) && block.has_attribute("twoslash")
+}
+
+/// The crate a doctest is compiled against.
+pub struct DoctestCrate {
+    /// The name of the package, which the doctest depends on
+    pub package: String,
+    /// The name of the crate, which the doctest uses
+    pub name: String,
+    pub root: PathBuf,
+    /// The root file of the crate, e.g. `src/lib.rs`
+    pub lib: PathBuf,
+    pub edition: Edition,
+}
+
+impl DoctestCrate {
+    /// Reads the crate at `root` the way cargo sees it, so that settings it inherits from its
+    /// workspace count.
+    pub fn new(root: &Path) -> Result<Self> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("could not find the crate at {}", root.display()))?;
+        let package = WorkspaceSettings {
+            root: root
+                .to_str()
+                .with_context(|| format!("{} is not UTF-8", root.display()))?,
+            package: None,
+        }
+        .package()?;
+        let lib = package.lib.with_context(|| {
+            format!(
+                "`{}` has no library target, so it has no doctests",
+                package.name
+            )
+        })?;
+        Ok(DoctestCrate {
+            name: lib.name,
+            package: package.name,
+            root: package.dir,
+            lib: lib.src_path,
+            edition: package.edition,
+        })
+    }
+
+    /// Turns a doctest into a snippet the way rustdoc turns it into a test: lines starting with
+    /// `# ` are hidden, `fn main` is added when missing, and the crate is a dependency. Along with
+    /// it comes the line of the doctest each line of the snippet comes from, or `None` for the
+    /// lines added around it.
+    ///
+    /// Only what comes before or after the shown lines can be cut from the result, so hidden
+    /// lines between shown ones are left in, for `hide_lines` to take out of the result.
+    pub fn snippet_lines(&self, doctest: &str) -> (String, Vec<Option<u32>>) {
+        let lines: Vec<(bool, &str)> = doctest.lines().map(doctest_line).collect();
+        let first_shown = lines.iter().position(|(hidden, _)| !hidden);
+        let last_shown = lines.iter().rposition(|(hidden, _)| !hidden);
+        let (first_shown, last_shown) = match (first_shown, last_shown) {
+            (Some(first), Some(last)) => (first, last + 1),
+            _ => (lines.len(), lines.len()),
+        };
+        let code = |range: std::ops::Range<usize>| -> Vec<(Option<u32>, String)> {
+            range
+                .map(|i| (Some(i as u32), lines[i].1.to_string()))
+                .collect()
+        };
+        let added = |line: String| (None, line);
+
+        // Crate attributes can't go in `fn main`.
+        let (attributes, before): (Vec<_>, Vec<_>) = code(0..first_shown)
+            .into_iter()
+            .partition(|(_, line)| line.trim_start().starts_with("#!["));
+        let mut snippet = vec![
+            added(format!("// @edition: {}", self.edition)),
+            added("// @crate-type: bin".to_string()),
+            added(format!(
+                "// @dependency: {} = {{ path = '{}' }}",
+                self.package,
+                self.root.display()
+            )),
+        ];
+        snippet.extend(attributes);
+        let uses_crate = doctest.contains(&self.name);
+        if uses_crate && !doctest.contains(&format!("extern crate {}", self.name)) {
+            snippet.push(added(format!("extern crate {};", self.name)));
+        }
+
+        let wrap = !doctest.contains("fn main");
+        // Like rustdoc, let doctests ending in `Ok(())` use `?`.
+        let returns_result = doctest.trim_end().ends_with("(())");
+        match (wrap, returns_result) {
+            (true, true) => snippet.push(added(
+                "fn main() { fn _inner() -> Result<(), impl core::fmt::Debug> {".to_string(),
+            )),
+            (true, false) => snippet.push(added("fn main() {".to_string())),
+            (false, _) => {}
+        }
+        snippet.extend(before);
+        snippet.push(added("// ---cut---".to_string()));
+        snippet.extend(code(first_shown..last_shown));
+        let after = code(last_shown..lines.len());
+        if wrap || !after.is_empty() {
+            snippet.push(added("// ---cut-after---".to_string()));
+        }
+        snippet.extend(after);
+        match (wrap, returns_result) {
+            (true, true) => snippet.push(added("} _inner().unwrap() }".to_string())),
+            (true, false) => snippet.push(added("}".to_string())),
+            (false, _) => {}
+        }
+        let (lines, code): (Vec<_>, Vec<_>) = snippet.into_iter().unzip();
+        (code.join("\n"), lines)
+    }
+
+    /// The crate a source file is part of, which is the nearest package above it.
+    pub fn of_file(file: &Path) -> Result<Self> {
+        let file = file
+            .canonicalize()
+            .with_context(|| format!("could not find {}", file.display()))?;
+        let root = file
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join("Cargo.toml").exists())
+            .ok_or_else(|| anyhow::anyhow!("{} is not part of a crate", file.display()))?;
+        DoctestCrate::new(root)
+    }
+}
+
+/// Whether a doctest line is hidden, and its code.
+fn doctest_line(line: &str) -> (bool, &str) {
+    let trimmed = line.trim();
+    if trimmed.starts_with("##") {
+        // `##` escapes a line that should start with `#`.
+        let hash = line.find('#').unwrap();
+        return (false, &line[hash + 1..]);
+    }
+    match trimmed.strip_prefix("# ") {
+        Some(code) => (true, code),
+        None if trimmed == "#" => (true, ""),
+        None => (false, line),
+    }
+}
+
+/// The doc comment of an item of a crate.
+pub struct ItemDocs {
+    /// The path of the item, e.g. `my_crate::module::Type::method`, or
+    /// `<my_crate::module::Type as Trait>::method` in a trait impl
+    pub path: String,
+    pub docs: String,
+}
+
+/// The doc comments of the items of the crate, found by following its module tree from the root
+/// file of its library.
+pub fn crate_docs(krate: &DoctestCrate) -> Result<Vec<ItemDocs>> {
+    let mut docs = vec![];
+    module_docs(
+        &krate.lib,
+        krate.lib.parent().unwrap(),
+        &krate.name,
+        krate.edition,
+        &mut docs,
+    )?;
+    Ok(docs)
+}
+
+/// Adds the docs of a module file and its items, whose submodules are in `dir`.
+fn module_docs(
+    file: &Path,
+    dir: &Path,
+    path: &str,
+    edition: Edition,
+    docs: &mut Vec<ItemDocs>,
+) -> Result<()> {
+    let text =
+        fs::read_to_string(file).with_context(|| format!("could not read {}", file.display()))?;
+    let source_file = ast::SourceFile::parse(&text, edition).tree();
+    push_docs(docs, path, source_file.doc_comments());
+    items_docs(source_file.items(), dir, path, edition, docs)
+}
+
+fn items_docs(
+    items: impl Iterator<Item = ast::Item>,
+    dir: &Path,
+    path: &str,
+    edition: Edition,
+    docs: &mut Vec<ItemDocs>,
+) -> Result<()> {
+    for item in items {
+        let name = |node: &SyntaxNode| ast::AnyHasName::cast(node.clone())?.name();
+        match item {
+            ast::Item::Module(module) => {
+                let Some(name) = module.name() else {
+                    continue;
+                };
+                let path = format!("{}::{}", path, name);
+                let dir = dir.join(name.text().as_str());
+                match module.item_list() {
+                    Some(list) => {
+                        let inner = DocCommentIter::from_syntax_node(list.syntax());
+                        push_docs(docs, &path, module.doc_comments().chain(inner));
+                        items_docs(list.items(), &dir, &path, edition, docs)?;
+                    }
+                    None => {
+                        push_docs(docs, &path, module.doc_comments());
+                        let files = [dir.with_extension("rs"), dir.join("mod.rs")];
+                        if let Some(file) = files.iter().find(|file| file.exists()) {
+                            module_docs(file, &dir, &path, edition, docs)?;
+                        }
+                    }
+                }
+            }
+            ast::Item::Impl(imp) => {
+                let Some(self_ty) = imp.self_ty() else {
+                    continue;
+                };
+                // Trait impls name their trait, so that e.g. the `fmt` of `Display` and of `Debug`
+                // don't share a path.
+                let path = match imp.trait_() {
+                    Some(trait_) => format!(
+                        "<{}::{} as {}>",
+                        path,
+                        type_path(&self_ty),
+                        type_path(&trait_)
+                    ),
+                    None => format!("{}::{}", path, type_path(&self_ty)),
+                };
+                for assoc in imp
+                    .assoc_item_list()
+                    .iter()
+                    .flat_map(|list| list.assoc_items())
+                {
+                    if let Some(name) = name(assoc.syntax()) {
+                        push_docs(docs, &format!("{}::{}", path, name), assoc.doc_comments());
+                    }
+                }
+            }
+            ast::Item::Trait(trait_) => {
+                let Some(name_) = trait_.name() else {
+                    continue;
+                };
+                let path = format!("{}::{}", path, name_);
+                push_docs(docs, &path, trait_.doc_comments());
+                for assoc in trait_
+                    .assoc_item_list()
+                    .iter()
+                    .flat_map(|list| list.assoc_items())
+                {
+                    if let Some(name) = name(assoc.syntax()) {
+                        push_docs(docs, &format!("{}::{}", path, name), assoc.doc_comments());
+                    }
+                }
+            }
+            item => {
+                let (Some(name), Some(item)) = (
+                    name(item.syntax()),
+                    ast::AnyHasDocComments::cast(item.syntax().clone()),
+                ) else {
+                    continue;
+                };
+                push_docs(docs, &format!("{}::{}", path, name), item.doc_comments());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How a type is named in the path of an item of its impl: its path without generic arguments,
+/// e.g. `Wrapper` for `Wrapper<T>`, or its text if it isn't a path, e.g. `[T]`.
+fn type_path(ty: &ast::Type) -> String {
+    let segments = match ty {
+        ast::Type::PathType(ty) => ty.path().map(|path| path.segments().collect::<Vec<_>>()),
+        _ => None,
+    };
+    let names: Option<Vec<_>> = segments
+        .into_iter()
+        .flatten()
+        .map(|segment| segment.name_ref().map(|name| name.text().to_string()))
+        .collect();
+    match names {
+        Some(names) if !names.is_empty() => names.join("::"),
+        _ => ty.syntax().text().to_string(),
+    }
+}
+
+/// Adds the docs of an item, if it has any. Items that end up with the same path, like methods of
+/// different impls of a generic trait, share one entry, with their docs one after the other.
+fn push_docs(docs: &mut Vec<ItemDocs>, path: &str, comments: impl Iterator<Item = ast::Comment>) {
+    let lines: Vec<_> = comments
+        .filter_map(|comment| {
+            let (text, _) = comment.doc_comment()?;
+            Some(text.strip_prefix(' ').unwrap_or(text).to_string())
+        })
+        .collect();
+    if lines.is_empty() {
+        return;
+    }
+    match docs.iter_mut().find(|item| item.path == path) {
+        Some(item) => {
+            item.docs.push_str("\n\n");
+            item.docs.push_str(&lines.join("\n"));
+        }
+        None => docs.push(ItemDocs {
+            path: path.to_string(),
+            docs: lines.join("\n"),
+        }),
+    }
+}
+
+/// Twoslashes the doctests of the crate at `root`, keyed by the path of the item they document
+/// and then by the index of the block among the code blocks of its docs.
+///
+/// Unlike `check`, which only looks at blocks tagged `twoslash`, every doctest is analyzed: a docs
+/// site shows hovers on all of its examples, while `cargo test --doc` already checks the ones
+/// without markers. Each result is passed to `render` along with the project that analyzed it.
+pub fn twoslash_crate<T>(
+    projects: &mut ProjectPool,
+    root: &Path,
+    mut render: impl FnMut(&Project, TwoSlash) -> Result<T>,
+) -> Result<BTreeMap<String, BTreeMap<usize, T>>> {
+    let krate = DoctestCrate::new(root)?;
+    let mut results = BTreeMap::new();
+    for item in crate_docs(&krate)? {
+        let mut item_results = BTreeMap::new();
+        for block in code_blocks(&item.docs).into_iter().filter(is_doctest) {
+            let (snippet, lines) = krate.snippet_lines(&block.code);
+            let result = projects
+                .project(&snippet)
+                .and_then(|project| {
+                    let mut result = project.twoslasher()?;
+                    hide_lines(&mut result, project, &block.code, &lines);
+                    render(project, result)
+                })
+                .with_context(|| format!("could not twoslash an example of {}", item.path))?;
+            item_results.insert(block.index, result);
+        }
+        if !item_results.is_empty() {
+            results.insert(item.path, item_results);
+        }
+    }
+    Ok(results)
+}
+
+/// Takes the hidden lines of a doctest that are between shown ones out of its result. `lines` are
+/// the lines of the doctest each line of its snippet comes from.
+fn hide_lines(result: &mut TwoSlash, project: &Project, doctest: &str, lines: &[Option<u32>]) {
+    let doctest: Vec<_> = doctest.lines().map(doctest_line).collect();
+    let hidden: Vec<u32> = (0..result.code.split('\n').count() as u32)
+        .filter(|&line| {
+            let snippet_line = project.result_line(line) as usize;
+            lines
+                .get(snippet_line)
+                .copied()
+                .flatten()
+                .is_some_and(|line| doctest[line as usize].0)
+        })
+        .collect();
+    result.hide_lines(&hidden);
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use ra_ide::Edition;
+    use tempfile::TempDir;
+
+    use super::{crate_docs, doc_code_blocks, should_twoslash, DoctestCrate};
+
+    #[test]
+    fn test_doc_code_blocks() {
+        let source = r#"//! Crate docs
+//!
+//! ```twoslash
+//! let x = 1;
+//! ```
+
+/// Adds one.
+///
+/// ```rust,no_run,twoslash
+/// let y = add_one(1);
+/// ```
+///
+/// ```text,twoslash
+/// not rust
+/// ```
+pub fn add_one(x: i32) -> i32 {
+    //// ```twoslash
+    x + 1
+}
+"#;
+        let blocks = doc_code_blocks(source);
+        assert_eq!(blocks.len(), 3);
+
+        assert_eq!(blocks[0].line, 2);
+        assert_eq!(blocks[0].code, "let x = 1;");
+        assert!(should_twoslash(&blocks[0]));
+
+        assert_eq!(blocks[1].index, 1);
+        assert_eq!(blocks[1].line, 8);
+        assert_eq!(blocks[1].code, "let y = add_one(1);");
+        assert!(should_twoslash(&blocks[1]));
+
+        assert!(!should_twoslash(&blocks[2]));
+    }
+
+    #[test]
+    fn test_doctest_crate_in_workspace() {
+        let workspace = TempDir::new().unwrap();
+        std::fs::write(
+            workspace.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"my-crate\"]\n\n[workspace.package]\nedition = \"2024\"\n",
+        )
+        .unwrap();
+        let root = workspace.path().join("my-crate");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(
+            root.join("Cargo.toml"),
+            "[package]\nname = \"my-crate\"\nversion = \"0.1.0\"\nedition.workspace = true\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("src/lib.rs"), "").unwrap();
+
+        let krate = DoctestCrate::new(&root).unwrap();
+        assert_eq!(krate.package, "my-crate");
+        assert_eq!(krate.name, "my_crate");
+        assert_eq!(krate.root, root.canonicalize().unwrap());
+        assert_eq!(krate.edition, Edition::Edition2024);
+    }
+
+    #[test]
+    fn test_doctest_crate_with_custom_lib() {
+        let root = TempDir::new().unwrap();
+        std::fs::write(
+            root.path().join("Cargo.toml"),
+            r#"
+[package]
+name = "my-crate"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+name = "shapes"
+path = "lib/api.rs"
+"#,
+        )
+        .unwrap();
+        std::fs::create_dir(root.path().join("lib")).unwrap();
+        std::fs::write(
+            root.path().join("lib/api.rs"),
+            "/// The area.\npub fn area() {}\npub mod square;\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.path().join("lib/square.rs"),
+            "/// A side.\npub fn side() {}\n",
+        )
+        .unwrap();
+
+        let krate = DoctestCrate::new(root.path()).unwrap();
+        assert_eq!(krate.package, "my-crate");
+        assert_eq!(krate.name, "shapes");
+        let paths: Vec<_> = crate_docs(&krate)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.path)
+            .collect();
+        assert_eq!(paths, ["shapes::area", "shapes::square::side"]);
+
+        // Doctests need a library to use
+        std::fs::write(
+            root.path().join("Cargo.toml"),
+            "[package]\nname = \"my-crate\"\nversion = \"0.1.0\"\n\n[[bin]]\nname = \"app\"\npath = \"lib/api.rs\"\n",
+        )
+        .unwrap();
+        let error = DoctestCrate::new(root.path()).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "`my-crate` has no library target, so it has no doctests"
+        );
+    }
+
+    #[test]
+    fn test_crate_docs_of_impls() {
+        let root = TempDir::new().unwrap();
+        std::fs::write(
+            root.path().join("Cargo.toml"),
+            "[package]\nname = \"shapes\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        std::fs::create_dir(root.path().join("src")).unwrap();
+        std::fs::write(
+            root.path().join("src/lib.rs"),
+            r#"
+pub struct Square<T>(T);
+
+impl<T> Square<T> {
+    /// Makes a square.
+    pub fn new(side: T) -> Self { Square(side) }
+}
+
+impl<T> std::fmt::Display for Square<T> {
+    /// Shows the square.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Ok(()) }
+}
+
+impl<T> std::fmt::Debug for Square<T> {
+    /// Debugs the square.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Ok(()) }
+}
+
+impl From<u8> for Square<u8> {
+    /// From a byte.
+    fn from(side: u8) -> Self { Square(side) }
+}
+
+impl From<u16> for Square<u16> {
+    /// From two bytes.
+    fn from(side: u16) -> Self { Square(side) }
+}
+"#,
+        )
+        .unwrap();
+
+        let krate = DoctestCrate::new(root.path()).unwrap();
+        let docs: Vec<_> = crate_docs(&krate)
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.path, item.docs))
+            .collect();
+        assert_eq!(
+            docs,
+            [
+                ("shapes::Square::new", "Makes a square."),
+                (
+                    "<shapes::Square as std::fmt::Display>::fmt",
+                    "Shows the square."
+                ),
+                (
+                    "<shapes::Square as std::fmt::Debug>::fmt",
+                    "Debugs the square."
+                ),
+                (
+                    "<shapes::Square as From>::from",
+                    "From a byte.\n\nFrom two bytes."
+                ),
+            ]
+            .map(|(path, docs)| (path.to_string(), docs.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_doctest_snippet() {
+        let krate = DoctestCrate {
+            package: "my-crate".to_string(),
+            name: "my_crate".to_string(),
+            root: PathBuf::from("/src/my-crate"),
+            lib: PathBuf::from("/src/my-crate/src/lib.rs"),
+            edition: Edition::Edition2021,
+        };
+        let doctest = "# #![allow(unused)]
+# use my_crate::double;
+let four = double(2);
+## not hidden
+# let _ = four;
+# Ok::<(), ()>(())";
+        assert_eq!(
+            krate.snippet_lines(doctest).0,
+            "// @edition: 2021
+// @crate-type: bin
+// @dependency: my-crate = { path = '/src/my-crate' }
+#![allow(unused)]
+extern crate my_crate;
+fn main() { fn _inner() -> Result<(), impl core::fmt::Debug> {
+use my_crate::double;
+// ---cut---
+let four = double(2);
+# not hidden
+// ---cut-after---
+let _ = four;
+Ok::<(), ()>(())
+} _inner().unwrap() }"
+        );
+        let (_, lines) = krate.snippet_lines(doctest);
+        assert_eq!(
+            lines,
+            [
+                None,
+                None,
+                None,
+                Some(0),
+                None,
+                None,
+                Some(1),
+                None,
+                Some(2),
+                Some(3),
+                None,
+                Some(4),
+                Some(5),
+                None
+            ]
+        );
+
+        // Doctests with their own `fn main` aren't wrapped
+        assert_eq!(
+            krate.snippet_lines("fn main() {}").0,
+            "// @edition: 2021
+// @crate-type: bin
+// @dependency: my-crate = { path = '/src/my-crate' }
+// ---cut---
+fn main() {}"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use crate::query_parser::{next_column, width};
+use crate::twoslash::{DiagnosticCategory, Error, Query, TwoSlash};
+
+const RESET: &str = "\x1b[0m";
+const BOLD_BLUE: &str = "\x1b[1;34m";
+const BOLD_CYAN: &str = "\x1b[1;36m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+
+/// Renders a twoslash result for a terminal: the code with line numbers, the answers to queries
+/// under the tokens they ask about, and errors underlined the way rustc does. Errors setting up
+/// the snippet come first, as they aren't about any of the code. Tabs take up `tab_width`
+/// columns, as they do when lining up markers.
+pub fn render(result: &TwoSlash, color: bool, tab_width: u32) -> String {
+    let paint = |style: &str, text: &str| match color {
+        true => format!("{}{}{}", style, text, RESET),
+        false => text.to_string(),
+    };
+
+    let code = &result.code;
+    let lines: Vec<&str> = code.split('\n').collect();
+    let number_width = lines.len().to_string().len();
+    let gutter = paint(BOLD_BLUE, &format!("{} |", " ".repeat(number_width)));
+
+    let mut output = String::new();
+    let (setup_errors, errors): (Vec<&Error>, Vec<&Error>) =
+        result.errors.iter().partition(|error| error.setup);
+    for error in setup_errors {
+        for message in error_lines(error) {
+            output.push_str(&paint(category_style(&error.category), &message));
+            output.push('\n');
+        }
+    }
+
+    let mut offset = 0;
+    for (line, text) in lines.iter().enumerate() {
+        let end = offset + text.len();
+        let on_line = |start: u32| (offset..=end).contains(&(start as usize));
+        // Columns count characters, so that markers line up under non-ASCII code, and tabs are
+        // expanded in the code too.
+        let column = |start: usize| width(&code[offset..start], tab_width) as usize;
+
+        output.push_str(&paint(BOLD_BLUE, &format!("{:>number_width$} |", line + 1)));
+        if !text.is_empty() {
+            output.push(' ');
+            output.push_str(&expand_tabs(text, tab_width));
+        }
+        output.push('\n');
+
+        for query in result.queries.iter().filter(|query| on_line(query.start)) {
+            let marker = format!("{}^ ", " ".repeat(column(query.start as usize)));
+            for (i, answer) in query_lines(query).iter().enumerate() {
+                let prefix = match i {
+                    0 => paint(BOLD_CYAN, &marker),
+                    _ => " ".repeat(marker.len()),
+                };
+                output.push_str(&format!("{} {}{}\n", gutter, prefix, answer));
+            }
+        }
+
+        for error in errors
+            .iter()
+            .filter(|error| on_line(error.start) && error.line as usize == line)
+        {
+            let style = category_style(&error.category);
+            let start = error.start as usize;
+            let error_end = end.min(start + error.length as usize).max(start);
+            let length = match code.is_char_boundary(error_end) {
+                true => column(error_end) - column(start),
+                false => 0,
+            };
+            let marker = format!(
+                "{}{} ",
+                " ".repeat(column(start)),
+                "^".repeat(length.max(1))
+            );
+            for (i, message) in error_lines(error).iter().enumerate() {
+                let prefix = match i {
+                    0 => marker.clone(),
+                    _ => " ".repeat(marker.len()),
+                };
+                output.push_str(&format!(
+                    "{} {}\n",
+                    gutter,
+                    paint(style, &(prefix + message))
+                ));
+            }
+        }
+        offset = end + 1;
+    }
+    output
+}
+
+/// `text` with its tabs replaced by the spaces they take up.
+fn expand_tabs(text: &str, tab_width: u32) -> String {
+    let mut expanded = String::new();
+    let mut column = 0;
+    for c in text.chars() {
+        let next = next_column(column, c, tab_width);
+        match c {
+            '\t' => expanded.push_str(&" ".repeat((next - column) as usize)),
+            _ => expanded.push(c),
+        }
+        column = next;
+    }
+    expanded
+}
+
+/// The lines of the answer to a query.
+fn query_lines(query: &Query) -> Vec<String> {
+    match (&query.completions, &query.text, &query.error) {
+        (Some(completions), _, _) => completions
+            .iter()
+            .map(|completion| completion.name.clone())
+            .collect(),
+        (None, Some(text), _) => text.lines().map(str::to_string).collect(),
+        (None, None, Some(error)) => error.lines().map(str::to_string).collect(),
+        (None, None, None) => vec![],
+    }
+}
+
+/// The lines of an error, the first one saying what kind of error it is, like rustc does.
+fn error_lines(error: &Error) -> Vec<String> {
+    let kind = error.category.name();
+    let mut lines: Vec<String> = error.rendered_message.lines().map(str::to_string).collect();
+    match lines.first_mut() {
+        Some(first) => *first = format!("{}: {}", kind, first),
+        None => lines.push(kind.to_string()),
+    }
+    lines
+}
+
+fn category_style(category: &DiagnosticCategory) -> &'static str {
+    match category {
+        DiagnosticCategory::Error => BOLD_RED,
+        DiagnosticCategory::Warning => BOLD_YELLOW,
+        DiagnosticCategory::Info | DiagnosticCategory::Debug => BOLD_BLUE,
+    }
+}
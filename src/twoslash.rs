@@ -1,20 +1,24 @@
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
 use ra_ide::Severity;
 use serde::Serialize;
 
 #[derive(Serialize)]
 pub struct Highlight {
-    kind: String,
+    pub kind: String,
     /// The index of the text in the file
-    start: u32,
+    pub start: u32,
     /// What line is the highlighted identifier on?
-    line: u32,
+    pub line: u32,
     /// At what index in the line does the caret represent
-    offset: u32,
+    pub offset: u32,
     /// The text of the token which is highlighted
     #[serde(skip_serializing_if = "Option::is_none")]
-    text: Option<String>,
+    pub text: Option<String>,
     /// The length of the token
-    length: u32,
+    pub length: u32,
 }
 
 #[derive(Serialize)]
@@ -43,6 +47,10 @@ pub enum QueryKind {
     Query,
     #[serde(rename = "completions")]
     Completions,
+    #[serde(rename = "constEval")]
+    ConstEval,
+    #[serde(rename = "assist")]
+    Assist,
 }
 
 #[derive(Serialize)]
@@ -50,6 +58,21 @@ pub struct CompletionEntry {
     pub name: String,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceEdit {
+    /// The index of the replaced text in the file
+    pub start: u32,
+    /// How much text is replaced
+    pub length: u32,
+    /// line number where the replaced text starts
+    pub line: u32,
+    /// The character on the line
+    pub character: u32,
+    /// The replacement text
+    pub new_text: String,
+}
+
 #[derive(Serialize)]
 pub struct Query {
     pub kind: QueryKind,
@@ -72,17 +95,26 @@ pub struct Query {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "completionsPrefix")]
     pub completions_prefix: Option<String>,
+    /// Why the const under the caret could not be evaluated, or the assist could not be applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The edits made by an assist, absent when some of them are outside the cut
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edits: Option<Vec<SourceEdit>>,
+    /// The code after the assist is applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transformed: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Tag {
     /// What was the name of the tag
-    name: String,
+    pub name: String,
     /// Where was it located in the original source file
-    line: u32,
+    pub line: u32,
     /// What was the text after the `// @tag: ` string  (optional because you could do // @tag on it's own line without the ':')
     #[serde(skip_serializing_if = "Option::is_none")]
-    annotation: Option<String>,
+    pub annotation: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -95,6 +127,18 @@ pub enum DiagnosticCategory {
     Error = 3,
 }
 
+impl DiagnosticCategory {
+    /// What the category is called, e.g. `error`
+    pub fn name(&self) -> &'static str {
+        match self {
+            DiagnosticCategory::Debug => "debug",
+            DiagnosticCategory::Info => "info",
+            DiagnosticCategory::Warning => "warning",
+            DiagnosticCategory::Error => "error",
+        }
+    }
+}
+
 impl From<Severity> for DiagnosticCategory {
     fn from(sev: Severity) -> Self {
         match sev {
@@ -117,6 +161,103 @@ pub struct Error {
     pub length: u32,
     pub line: u32,
     pub character: u32,
+    /// Quick fixes rust-analyzer offers for this error, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixes: Option<Vec<Fix>>,
+    /// Whether the error is about setting up the snippet, like an invalid directive, rather than
+    /// about its code, in which case its position means nothing
+    #[serde(skip)]
+    pub setup: bool,
+}
+
+#[derive(Serialize)]
+pub struct Fix {
+    /// What the fix does, e.g. "Change it to be mutable"
+    pub label: String,
+    /// The edits the fix makes
+    pub edits: Vec<SourceEdit>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AdjustmentKind {
+    /// Auto-deref, auto-borrow, reborrows and other coercions
+    Adjustment,
+    /// `ref`/`&` inserted by match ergonomics
+    BindingMode,
+    /// Values dropped at the end of their scope
+    Drop,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AdjustmentPosition {
+    Before,
+    After,
+}
+
+#[derive(Serialize)]
+pub struct Adjustment {
+    pub kind: AdjustmentKind,
+    /// The code the compiler inserts, e.g. `&*` or `drop(x)`
+    pub label: String,
+    /// Whether the label goes before or after the token
+    pub position: AdjustmentPosition,
+    /// The index of the text in the file
+    pub start: u32,
+    /// The length of the token the label is attached to
+    pub length: u32,
+    /// line number where this is found
+    pub line: u32,
+    /// The character on the line
+    pub character: u32,
+}
+
+/// Why `cargo check` failed while setting up the project, e.g. a broken Cargo.toml or a
+/// dependency that doesn't exist. Errors in the snippet itself are reported as diagnostics instead.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootstrapError {
+    /// The errors cargo reported, each with the details that followed it
+    pub messages: Vec<String>,
+    /// The dependencies cargo could not find, download or pick a version of
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved_dependencies: Vec<String>,
+    /// Everything cargo wrote to stderr
+    pub stderr: String,
+}
+
+impl fmt::Display for BootstrapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to set up the project")?;
+        if !self.unresolved_dependencies.is_empty() {
+            let names: Vec<_> = self
+                .unresolved_dependencies
+                .iter()
+                .map(|name| format!("`{}`", name))
+                .collect();
+            write!(f, ", could not resolve {}", names.join(", "))?;
+        }
+        write!(f, ": {}", self.messages.join("\n"))
+    }
+}
+
+impl std::error::Error for BootstrapError {}
+
+/// How long each phase of producing the result took, in milliseconds
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    /// Writing the project and running `cargo check`, absent when an existing project was reused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootstrap: Option<f64>,
+    /// Loading the project into rust-analyzer, absent when an existing project was reused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load: Option<f64>,
+    /// Finding the tokens of the snippet and their hovers
+    pub index: f64,
+    /// Diagnostics, queries and everything else in the result
+    pub analysis: f64,
 }
 
 #[derive(Serialize)]
@@ -134,7 +275,247 @@ pub struct TwoSlash {
     pub tags: Vec<Tag>,
     /// Diagnostic error messages which came up when creating the program
     pub errors: Vec<Error>,
+    /// Code the compiler inserts implicitly, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adjustments: Option<Vec<Adjustment>>,
+    /// Why the project could not be set up properly, which makes the analysis unreliable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootstrap_error: Option<BootstrapError>,
+    /// How long each phase took, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
     /// The URL for this sample in the playground
     #[serde(rename = "playgroundURL")]
     pub playground_url: String,
 }
+
+impl TwoSlash {
+    /// Converts every position in the result from UTF-8 bytes, which rust-analyzer counts in, to
+    /// `encoding`.
+    pub fn encode_positions(&mut self, encoding: PositionEncoding) {
+        if encoding == PositionEncoding::Utf8 {
+            return;
+        }
+        let encoder = PositionEncoder::new(&self.code, encoding);
+        for highlight in &mut self.highlights {
+            encoder.encode(
+                &mut highlight.start,
+                &mut highlight.length,
+                &mut highlight.offset,
+            );
+        }
+        for info in &mut self.static_quick_infos {
+            encoder.encode(&mut info.start, &mut info.length, &mut info.character);
+        }
+        for query in &mut self.queries {
+            encoder.encode(&mut query.start, &mut query.length, &mut query.offset);
+            for edit in query.edits.iter_mut().flatten() {
+                encoder.encode_edit(edit);
+            }
+        }
+        for error in &mut self.errors {
+            encoder.encode(&mut error.start, &mut error.length, &mut error.character);
+            for fix in error.fixes.iter_mut().flatten() {
+                for edit in &mut fix.edits {
+                    encoder.encode_edit(edit);
+                }
+            }
+        }
+        for adjustment in self.adjustments.iter_mut().flatten() {
+            encoder.encode(
+                &mut adjustment.start,
+                &mut adjustment.length,
+                &mut adjustment.character,
+            );
+        }
+    }
+
+    /// Takes `lines` of the code out of the result, like the hidden lines of a doctest, along with
+    /// whatever is on them, and moves what comes after them up. Fixes and assists that would edit
+    /// the lines are dropped, as applying them to the shown code would break it. The transformed
+    /// code of an assist is left as it is.
+    pub fn hide_lines(&mut self, lines: &[u32]) {
+        if lines.is_empty() {
+            return;
+        }
+        let hider = LineHider::new(&self.code, lines);
+        self.code = hider.code.clone();
+
+        self.highlights.retain_mut(|highlight| {
+            hider.hide(&mut highlight.start, highlight.length, &mut highlight.line)
+        });
+        self.static_quick_infos
+            .retain_mut(|info| hider.hide(&mut info.start, info.length, &mut info.line));
+        self.queries.retain_mut(|query| {
+            hider.hide(&mut query.start, query.length, &mut query.line)
+                && query
+                    .edits
+                    .iter_mut()
+                    .flatten()
+                    .all(|edit| hider.hide_edit(edit))
+        });
+        self.errors.retain_mut(|error| {
+            if let Some(fixes) = &mut error.fixes {
+                fixes.retain_mut(|fix| fix.edits.iter_mut().all(|edit| hider.hide_edit(edit)));
+            }
+            // Errors setting up the snippet aren't anywhere in the code.
+            error.setup || hider.hide(&mut error.start, error.length, &mut error.line)
+        });
+        if let Some(adjustments) = &mut self.adjustments {
+            adjustments.retain_mut(|adjustment| {
+                hider.hide(
+                    &mut adjustment.start,
+                    adjustment.length,
+                    &mut adjustment.line,
+                )
+            });
+        }
+    }
+}
+
+/// Moves positions in some code to where they are once some of its lines are taken out.
+struct LineHider {
+    /// The code without the lines
+    code: String,
+    /// The lines taken out, in order
+    lines: Vec<u32>,
+    /// The byte ranges of the lines taken out, line endings included, in order
+    ranges: Vec<Range<u32>>,
+}
+
+impl LineHider {
+    fn new(code: &str, lines: &[u32]) -> Self {
+        let mut lines = lines.to_vec();
+        lines.sort();
+        lines.dedup();
+
+        let mut kept = String::new();
+        let mut ranges = vec![];
+        let mut start = 0;
+        for (i, line) in code.split_inclusive('\n').enumerate() {
+            let end = start + line.len() as u32;
+            match lines.binary_search(&(i as u32)) {
+                Ok(_) => ranges.push(start..end),
+                Err(_) => kept.push_str(line),
+            }
+            start = end;
+        }
+        // The code ends with its last line, not a line ending.
+        if ranges
+            .last()
+            .is_some_and(|range| range.end as usize == code.len())
+        {
+            kept.pop();
+            if kept.ends_with('\r') {
+                kept.pop();
+            }
+        }
+        LineHider {
+            code: kept,
+            lines,
+            ranges,
+        }
+    }
+
+    /// Moves the range at `start` and the `line` it is on, or returns `false` if any of it is
+    /// taken out.
+    fn hide(&self, start: &mut u32, length: u32, line: &mut u32) -> bool {
+        let end = *start + length;
+        let overlaps =
+            |range: &Range<u32>| range.contains(start) || (range.start < end && *start < range.end);
+        if self.ranges.iter().any(overlaps) {
+            return false;
+        }
+        let removed: u32 = self
+            .ranges
+            .iter()
+            .filter(|range| range.end <= *start)
+            .map(|range| range.len() as u32)
+            .sum();
+        *start -= removed;
+        *line -= self.lines.partition_point(|&hidden| hidden < *line) as u32;
+        true
+    }
+
+    fn hide_edit(&self, edit: &mut SourceEdit) -> bool {
+        self.hide(&mut edit.start, edit.length, &mut edit.line)
+    }
+}
+
+/// What positions in the output count
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    /// UTF-8 bytes, which is how Rust indexes strings
+    #[default]
+    Utf8,
+    /// UTF-16 code units, which is how JavaScript indexes strings
+    Utf16,
+    /// Characters, which is how Python indexes strings
+    Utf32,
+}
+
+impl FromStr for PositionEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "utf-8" => Ok(PositionEncoding::Utf8),
+            "utf-16" => Ok(PositionEncoding::Utf16),
+            "utf-32" => Ok(PositionEncoding::Utf32),
+            _ => Err(anyhow::anyhow!("invalid position encoding: {:?}", s)),
+        }
+    }
+}
+
+impl PositionEncoding {
+    /// How long `text` is in this encoding
+    fn len(self, text: &str) -> u32 {
+        let len = match self {
+            PositionEncoding::Utf8 => text.len(),
+            PositionEncoding::Utf16 => text.encode_utf16().count(),
+            PositionEncoding::Utf32 => text.chars().count(),
+        };
+        len as u32
+    }
+}
+
+/// Converts positions in some code from UTF-8 bytes to another encoding. Positions that don't
+/// fall on a character of the code are left as they are.
+pub struct PositionEncoder<'a> {
+    code: &'a str,
+    /// The encoded position of each byte position of the code, so that converting a range takes
+    /// no more than looking up its ends
+    positions: Vec<u32>,
+}
+
+impl<'a> PositionEncoder<'a> {
+    pub fn new(code: &'a str, encoding: PositionEncoding) -> Self {
+        let mut positions = Vec::with_capacity(code.len() + 1);
+        let mut position = 0;
+        for c in code.chars() {
+            let len = encoding.len(c.encode_utf8(&mut [0; 4]));
+            // The bytes inside a character share its start, but are never looked up
+            positions.extend(std::iter::repeat_n(position, c.len_utf8()));
+            position += len;
+        }
+        positions.push(position);
+        PositionEncoder { code, positions }
+    }
+
+    /// Converts the `start` and `length` of a range, and the `character` on the line it starts.
+    pub fn encode(&self, start: &mut u32, length: &mut u32, character: &mut u32) {
+        let (byte_start, byte_end) = (*start as usize, (*start + *length) as usize);
+        let line_start = byte_start.saturating_sub(*character as usize);
+        let encode = |range: Range<usize>, fallback: u32| match self.code.get(range.clone()) {
+            Some(_) => self.positions[range.end] - self.positions[range.start],
+            None => fallback,
+        };
+        *character = encode(line_start..byte_start, *character);
+        *length = encode(byte_start..byte_end, *length);
+        *start = encode(0..byte_start, *start);
+    }
+
+    fn encode_edit(&self, edit: &mut SourceEdit) {
+        self.encode(&mut edit.start, &mut edit.length, &mut edit.character);
+    }
+}